@@ -325,6 +325,7 @@ impl State {
                         lua_resetthread(self.state);
                     }
                     self.drop_slots_greater(self.get_top());
+                    self.record_error(&err);
                     return Err(err);
                 }
             }
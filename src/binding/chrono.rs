@@ -1,6 +1,6 @@
 use crate::prelude::*;
 use ::chrono::prelude::*;
-use alloc::string::ToString;
+use alloc::{format, string::ToString};
 use chrono::{Days, Duration, Months};
 use core::{fmt::Display, str::FromStr};
 
@@ -258,6 +258,62 @@ where
     }
 }
 
+/// Represents a time as the seconds since unix epoch, possibly fractional, like the `SystemTime` binding does.
+/// It can be converted from a number timestamp, an ISO-8601 string or the userdata of the time,
+/// [`NaiveDateTime`] is regarded as in UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timestamp<T>(pub T);
+
+impl ToLua for Timestamp<DateTime<Utc>> {
+    fn to_lua<'a>(self, s: &'a LuaState) -> LuaResult<ValRef<'a>> {
+        let secs = self.0.timestamp() as f64 + self.0.timestamp_subsec_nanos() as f64 / 1e9;
+        secs.to_lua(s)
+    }
+}
+
+impl ToLua for Timestamp<NaiveDateTime> {
+    fn to_lua<'a>(self, s: &'a LuaState) -> LuaResult<ValRef<'a>> {
+        Timestamp(self.0.and_utc()).to_lua(s)
+    }
+}
+
+impl<'a> FromLua<'a> for Timestamp<DateTime<Utc>> {
+    fn from_lua(s: &'a LuaState, val: ValRef<'a>) -> LuaResult<Self> {
+        let ty = val.type_of();
+        let dt = match val.into_value() {
+            LuaValue::Integer(secs) => Utc.timestamp_opt(secs, 0).single(),
+            LuaValue::Number(secs) => {
+                let whole = secs.floor();
+                let nanos = ((secs - whole) * 1e9).round().min(999_999_999.0);
+                (whole.is_finite() && whole.abs() < i64::MAX as f64)
+                    .then(|| Utc.timestamp_opt(whole as i64, nanos as u32).single())
+                    .flatten()
+            }
+            LuaValue::String(text) => {
+                let text = text.to_str().unwrap_or_default();
+                return DateTime::parse_from_rfc3339(text)
+                    .map(|dt| Self(dt.with_timezone(&Utc)))
+                    .or_else(|_| NaiveDateTime::from_str(text).map(|dt| Self(dt.and_utc())))
+                    .map_err(|err| LuaError::convert(format!("invalid datetime {text:?}: {err}")));
+            }
+            LuaValue::UserData(u) => {
+                return <&DateTime<Utc>>::from_lua(s, u.0.clone())
+                    .map(|dt| Self(*dt))
+                    .or_else(|_| <&NaiveDateTime>::from_lua(s, u.0).map(|dt| Self(dt.and_utc())));
+            }
+            _ => return Err(LuaError::TypeNotMatch(ty)),
+        };
+        dt.map(Self)
+            .ok_or_else(|| LuaError::convert("timestamp out of range"))
+    }
+}
+
+impl<'a> FromLua<'a> for Timestamp<NaiveDateTime> {
+    fn from_lua(s: &'a LuaState, val: ValRef<'a>) -> LuaResult<Self> {
+        Timestamp::<DateTime<Utc>>::from_lua(s, val).map(|Timestamp(dt)| Self(dt.naive_utc()))
+    }
+}
+
 pub fn open(lua: &LuaState) -> LuaResult<LuaTable> {
     let m = lua.new_table()?;
 
@@ -277,6 +333,9 @@ pub fn open(lua: &LuaState) -> LuaResult<LuaTable> {
     m.set("NaiveDate", lua.register_usertype::<NaiveDate>()?)?;
     m.set("NaiveTime", lua.register_usertype::<NaiveTime>()?)?;
     m.set("NaiveDateTime", lua.register_usertype::<NaiveDateTime>()?)?;
+    m.set_closure("time_iso", |Timestamp(dt): Timestamp<DateTime<Utc>>| {
+        dt.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+    })?;
 
     Ok(m)
 }
@@ -23,7 +23,7 @@ pub fn open(lua: &LuaState) -> LuaResult<LuaTable> {
         } else {
             fs::hard_link(old, new)
         }
-        .lua_result()
+        .map_err(LuaError::from)
     })?;
 
     module.set_closure("readlink", |path: &str| NilError(std::fs::read_link(path)))?;
@@ -56,7 +56,7 @@ pub fn open(lua: &LuaState) -> LuaResult<LuaTable> {
                     key.replace(arg2);
                 }
             }
-            let meta = fs::metadata(path).lua_result()?;
+            let meta = fs::metadata(path)?;
             let res = lua_attribute(lua, res, path, meta)?;
             if let Some(key) = key {
                 res.get(key).map(ValRef::into_value)
@@ -73,7 +73,7 @@ pub fn open(lua: &LuaState) -> LuaResult<LuaTable> {
                 return LuaResult::Ok(LuaValue::Nil);
             }
 
-            let meta = fs::symlink_metadata(path).lua_result()?;
+            let meta = fs::symlink_metadata(path)?;
             let res = lua_attribute(lua, None, path, meta)?;
             res.set(
                 "target",
@@ -91,7 +91,7 @@ pub fn open(lua: &LuaState) -> LuaResult<LuaTable> {
     )?;
 
     module.set_closure("touch", |file: &str, t: Option<f64>, a: Option<f64>| {
-        let file = fs::File::open(file).lua_result()?;
+        let file = fs::File::open(file)?;
         file.set_times(
             FileTimes::new()
                 .set_modified(
@@ -103,7 +103,7 @@ pub fn open(lua: &LuaState) -> LuaResult<LuaTable> {
                         .unwrap_or(SystemTime::now()),
                 ),
         )
-        .lua_result()
+        .map_err(LuaError::from)
     })?;
 
     Ok(module)
@@ -1,6 +1,9 @@
+use alloc::vec::Vec;
+use serde::{ser::SerializeMap, Serialize, Serializer};
+use serde_json::ser::PrettyFormatter;
 use std::{
     fs::File,
-    io::{BufReader, BufWriter},
+    io::{BufReader, BufWriter, Write},
 };
 
 use crate::{impl_fromlua_as_serde, impl_tolua_as_serde, prelude::*};
@@ -8,6 +11,24 @@ use crate::{impl_fromlua_as_serde, impl_tolua_as_serde, prelude::*};
 impl_tolua_as_serde!(serde_json::Value);
 impl_fromlua_as_serde!(owned serde_json::Value);
 
+impl LuaState {
+    /// Load the json text into a lua value directly, without the intermediate [`serde_json::Value`],
+    /// the nulls are loaded as nil, like `json.load` in lua
+    pub fn from_json(&self, bytes: &[u8]) -> LuaResult<ValRef> {
+        self.load_from_deserializer(&mut serde_json::Deserializer::from_slice(bytes))
+    }
+}
+
+impl ValRef<'_> {
+    /// Encode this value as json text directly, without the intermediate [`serde_json::Value`]
+    pub fn to_json_vec(&self) -> LuaResult<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.transcode(&mut serde_json::Serializer::new(&mut buf))
+            .lua_result()?;
+        Ok(buf)
+    }
+}
+
 pub fn open(s: &LuaState) -> LuaResult<LuaTable> {
     let m = s.new_table()?;
     m.set(
@@ -24,15 +45,27 @@ pub fn open(s: &LuaState) -> LuaResult<LuaTable> {
             )))
         })?,
     )?;
-    m.set_closure("dump", |val: ValRef, pretty: LuaValue| match pretty {
-        LuaValue::Bool(true) => serde_json::to_vec_pretty(&val).map(LuaBytes),
-        _ => serde_json::to_vec(&val).map(LuaBytes),
+    // unlike `load`, the nulls are decoded as `json.null` rather than nil, to keep the entries of null
+    m.set(
+        "decode",
+        s.new_closure1(|s: &LuaState, buf: &[u8]| {
+            s.load_from_deserializer_with_null(&mut serde_json::Deserializer::from_slice(buf))
+        })?,
+    )?;
+    // the second argument is `true` for pretty printing, or a table of `{pretty, sort_keys, indent}`
+    m.set_closure("dump", |val: ValRef, opts: LuaValue| {
+        let mut buf = Vec::new();
+        DumpOptions::from_value(opts)?.write(&mut buf, &val)?;
+        LuaResult::Ok(LuaBytes(buf))
     })?;
+    m.set("encode", m.get("dump")?)?;
+    // the null of json decoded by `decode`, which is encoded as null
+    m.set("null", s.null_value())?;
     m.set_closure("dump_pretty", |val: ValRef| {
         serde_json::to_vec_pretty(&val).map(LuaBytes)
     })?;
-    m.set_closure("dumpfile", |path: &str, val: ValRef| {
-        serde_json::to_writer(BufWriter::new(File::create(path).lua_result()?), &val).lua_result()
+    m.set_closure("dumpfile", |path: &str, val: ValRef, opts: LuaValue| {
+        DumpOptions::from_value(opts)?.write(BufWriter::new(File::create(path)?), &val)
     })?;
     m.set_closure("print", |val: ValRef| {
         serde_json::to_writer(&mut std::io::stdout(), &val)
@@ -43,3 +76,72 @@ pub fn open(s: &LuaState) -> LuaResult<LuaTable> {
 
     Ok(m)
 }
+
+/// Options of `json.dump`, the keys are sorted by converting to [`serde_json::Value`] first
+#[derive(Default)]
+struct DumpOptions {
+    pretty: bool,
+    sort_keys: bool,
+    indent: Option<usize>,
+}
+
+impl DumpOptions {
+    fn from_value(opts: LuaValue) -> LuaResult<Self> {
+        Ok(match opts {
+            LuaValue::Bool(pretty) => Self {
+                pretty,
+                ..Default::default()
+            },
+            LuaValue::Table(t) => Self {
+                pretty: t.getopt::<_, bool>("pretty")?.unwrap_or_default(),
+                sort_keys: t.getopt::<_, bool>("sort_keys")?.unwrap_or_default(),
+                indent: t.getopt("indent")?,
+            },
+            _ => Self::default(),
+        })
+    }
+
+    fn write(&self, writer: impl Write, val: &ValRef) -> LuaResult<()> {
+        if self.sort_keys {
+            let val = serde_json::to_value(val).lua_result()?;
+            self.serialize(writer, &SortedKeys(&val))
+        } else {
+            self.serialize(writer, val)
+        }
+        .lua_result()
+    }
+
+    fn serialize(&self, writer: impl Write, val: &impl Serialize) -> serde_json::Result<()> {
+        if self.pretty || self.indent.is_some() {
+            let indent = b" ".repeat(self.indent.unwrap_or(2));
+            let formatter = PrettyFormatter::with_indent(&indent);
+            val.serialize(&mut serde_json::Serializer::with_formatter(
+                writer, formatter,
+            ))
+        } else {
+            serde_json::to_writer(writer, val)
+        }
+    }
+}
+
+/// Serialize the object entries sorted by key, the order of [`serde_json::Map`] can't be relied on,
+/// which keeps the insertion order if the `preserve_order` feature of serde_json is enabled
+struct SortedKeys<'a>(&'a serde_json::Value);
+
+impl Serialize for SortedKeys<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0 {
+            serde_json::Value::Object(map) => {
+                let mut entries = map.iter().collect::<Vec<_>>();
+                entries.sort_by(|a, b| a.0.cmp(b.0));
+                let mut m = serializer.serialize_map(Some(entries.len()))?;
+                for (k, v) in entries {
+                    m.serialize_entry(k, &SortedKeys(v))?;
+                }
+                m.end()
+            }
+            serde_json::Value::Array(arr) => serializer.collect_seq(arr.iter().map(SortedKeys)),
+            val => val.serialize(serializer),
+        }
+    }
+}
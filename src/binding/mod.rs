@@ -10,12 +10,16 @@ pub mod fs;
 pub mod json;
 #[cfg(feature = "log")]
 pub mod log;
+#[cfg(feature = "msgpack")]
+pub mod msgpack;
 #[cfg(feature = "regex")]
 pub mod regex;
 #[cfg(feature = "std")]
 pub mod std;
 #[cfg(feature = "tokio")]
 pub mod tokio;
+#[cfg(feature = "toml")]
+pub mod toml;
 
 pub fn init_global(s: &State) -> Result<()> {
     #[cfg(feature = "std")]
@@ -26,6 +30,8 @@ pub fn init_global(s: &State) -> Result<()> {
     s.register_module("regex", regex::open, false)?;
     #[cfg(feature = "json")]
     s.register_module("json", json::open, false)?;
+    #[cfg(feature = "msgpack")]
+    s.register_module("msgpack", msgpack::open, false)?;
     #[cfg(feature = "tokio")]
     s.register_module("tokio", tokio::open, false)?;
 
@@ -0,0 +1,24 @@
+use alloc::vec::Vec;
+
+use crate::prelude::*;
+
+pub fn open(s: &LuaState) -> LuaResult<LuaTable> {
+    let m = s.new_table()?;
+    // the tables with array metatable or sequence are encoded as arrays, the others as maps
+    m.set_closure("encode", |val: ValRef| {
+        let mut buf = Vec::new();
+        val.transcode(&mut rmp_serde::Serializer::new(&mut buf))
+            .lua_result()?;
+        LuaResult::Ok(LuaBytes(buf))
+    })?;
+    m.set(
+        "decode",
+        s.new_closure1(|s: &LuaState, buf: &[u8]| {
+            s.load_from_deserializer_with_null(&mut rmp_serde::Deserializer::from_read_ref(buf))
+        })?,
+    )?;
+    // the nil of msgpack is decoded as this value, to keep the entries of nil
+    m.set("null", s.null_value())?;
+
+    Ok(m)
+}
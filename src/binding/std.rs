@@ -4,7 +4,6 @@ use alloc::vec::Vec;
 use core::{ops::Range, result::Result as StdResult};
 
 use crate::error::Result;
-use crate::marker::ArgRef;
 use crate::prelude::*;
 use crate::userdata::UserdataRegistry;
 
@@ -142,15 +141,69 @@ pub mod time {
     use super::*;
     use std::time::*;
 
+    /// Signed duration since unix epoch, negative if `time` is before the epoch
+    fn since_epoch(time: SystemTime) -> (bool, Duration) {
+        match time.duration_since(UNIX_EPOCH) {
+            Ok(dur) => (false, dur),
+            Err(err) => (true, err.duration()),
+        }
+    }
+
+    fn from_epoch(negative: bool, dur: Duration) -> Result<SystemTime> {
+        if negative {
+            UNIX_EPOCH.checked_sub(dur)
+        } else {
+            UNIX_EPOCH.checked_add(dur)
+        }
+        .ok_or_else(|| LuaError::convert("time out of range"))
+    }
+
+    /// Converted to the seconds since unix epoch, the negative value is before the epoch
     impl ToLua for SystemTime {
         fn to_lua<'a>(self, s: &'a LuaState) -> Result<ValRef<'a>> {
-            self.duration_since(UNIX_EPOCH)
+            let (negative, dur) = since_epoch(self);
+            let secs = dur.as_secs_f64();
+            (if negative { -secs } else { secs }).to_lua(s)
+        }
+    }
+
+    /// Reconstruct from the seconds since unix epoch, the negative value is before the epoch
+    impl<'a> FromLua<'a> for SystemTime {
+        fn from_lua(_: &'a LuaState, val: ValRef<'a>) -> Result<Self> {
+            let ty = val.type_of();
+            let secs = match val.into_value() {
+                LuaValue::Integer(n) => n as f64,
+                LuaValue::Number(n) => n,
+                _ => return Err(LuaError::TypeNotMatch(ty)),
+            };
+            let dur = Duration::try_from_secs_f64(secs.abs()).map_err(LuaError::runtime_debug)?;
+            from_epoch(secs < 0.0, dur)
+        }
+    }
+
+    /// Represents a [`SystemTime`] as the integer nanoseconds since unix epoch,
+    /// which keeps the full precision, unlike the float seconds
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct UnixNanos(pub SystemTime);
+
+    /// The time before the epoch is negative, and nil if it's out of the range of `i64`
+    impl ToLua for UnixNanos {
+        fn to_lua<'a>(self, s: &'a LuaState) -> Result<ValRef<'a>> {
+            let (negative, dur) = since_epoch(self.0);
+            let nanos = dur.as_nanos() as i128;
+            i64::try_from(if negative { -nanos } else { nanos })
                 .ok()
-                .map(|dur| dur.as_secs_f64())
                 .to_lua(s)
         }
     }
 
+    impl<'a> FromLua<'a> for UnixNanos {
+        fn from_lua(s: &'a LuaState, val: ValRef<'a>) -> Result<Self> {
+            let nanos = i64::from_lua(s, val)?;
+            from_epoch(nanos < 0, Duration::from_nanos(nanos.unsigned_abs())).map(Self)
+        }
+    }
+
     impl ToLua for Duration {
         fn to_lua<'a>(self, s: &'a LuaState) -> LuaResult<ValRef<'a>> {
             self.as_secs_f64().to_lua(s)
@@ -261,31 +314,31 @@ pub mod process {
         fn methods(mt: UserdataRegistry<Self>) -> Result<()> {
             mt.add_mut("arg", |this: &mut Self, arg: &str| {
                 this.arg(arg);
-                ArgRef(1)
+                Chainable
             })?;
             mt.add_mut("args", |this: &mut Self, arg: Vec<String>| {
                 this.args(arg.as_slice());
-                ArgRef(1)
+                Chainable
             })?;
             mt.add_mut("current_dir", |this: &mut Self, arg: &str| {
                 this.current_dir(arg);
-                ArgRef(1)
+                Chainable
             })?;
             mt.add_mut("env_clear", |this: &mut Self| {
                 this.env_clear();
-                ArgRef(1)
+                Chainable
             })?;
             mt.add_mut("stdin", |this: &mut Self, arg: Stdio| {
                 this.stdin(arg);
-                ArgRef(1)
+                Chainable
             })?;
             mt.add_mut("stdout", |this: &mut Self, arg: Stdio| {
                 this.stdout(arg);
-                ArgRef(1)
+                Chainable
             })?;
             mt.add_mut("stderr", |this: &mut Self, arg: Stdio| {
                 this.stderr(arg);
-                ArgRef(1)
+                Chainable
             })?;
             mt.add_mut("env", |this: &mut Self, k: &str, v: Option<&str>| {
                 if let Some(v) = v {
@@ -293,7 +346,7 @@ pub mod process {
                 } else {
                     this.env_remove(k);
                 }
-                ArgRef(1)
+                Chainable
             })?;
             mt.add_mut("spawn", |this: &mut Self| this.spawn())?;
 
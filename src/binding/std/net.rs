@@ -1,8 +1,5 @@
 use crate::prelude::*;
-use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
-
-crate::impl_tolua_as_serde!(SocketAddr);
-crate::impl_fromlua_as_serde!(SocketAddr);
+use std::net::{Shutdown, TcpListener, TcpStream};
 
 impl UserData for TcpListener {
     fn getter(fields: UserdataRegistry<Self>) -> LuaResult<()> {
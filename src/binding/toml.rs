@@ -0,0 +1,32 @@
+use alloc::string::ToString;
+
+use crate::prelude::*;
+
+/// TOML datetime is represented as string in lua
+fn datetime_to_string(val: toml::Value) -> toml::Value {
+    match val {
+        toml::Value::Datetime(dt) => toml::Value::String(dt.to_string()),
+        toml::Value::Array(arr) => {
+            toml::Value::Array(arr.into_iter().map(datetime_to_string).collect())
+        }
+        toml::Value::Table(t) => toml::Value::Table(
+            t.into_iter()
+                .map(|(k, v)| (k, datetime_to_string(v)))
+                .collect(),
+        ),
+        val => val,
+    }
+}
+
+impl ToLua for toml::Value {
+    fn to_lua<'a>(self, s: &'a LuaState) -> LuaResult<ValRef<'a>> {
+        s.load_from_deserializer(datetime_to_string(self))
+    }
+}
+
+impl ValRef<'_> {
+    /// Convert this lua value to TOML value
+    pub fn to_toml(&self) -> LuaResult<toml::Value> {
+        toml::Value::try_from(self).map_err(|err| LuaError::convert(err.to_string()))
+    }
+}
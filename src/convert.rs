@@ -4,7 +4,7 @@ use crate::{
     error::{Error, Result, ToLuaResult},
     ffi::{self, *},
     luaapi::*,
-    marker::{IterVec, Pushed, Strict},
+    marker::{Checked, IterVec, MultiValue, Pushed, Strict},
     prelude::StaticIter,
     state::State,
     userdata::{UserData, UserDataTrans},
@@ -14,6 +14,7 @@ use crate::{
 use alloc::{
     borrow::{Cow, ToOwned},
     boxed::Box,
+    collections::{BTreeMap, BTreeSet},
     ffi::CString,
     string::String,
     sync::Arc,
@@ -27,7 +28,7 @@ use core::{
 
 #[cfg(feature = "std")]
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet, LinkedList, VecDeque},
     ffi::{OsStr, OsString},
     hash::Hash,
 };
@@ -88,6 +89,10 @@ pub trait ToLua: Sized {
     #[doc(hidden)]
     const __PUSH: Option<fn(Self, &State) -> Result<()>> = None;
 
+    /// Convert scalar values to [`Value`] directly, without touching the lua stack
+    #[doc(hidden)]
+    const __SCALAR: Option<fn(Self) -> Value<'static>> = None;
+
     fn to_lua<'a>(self, lua: &'a State) -> Result<ValRef<'a>> {
         if let Some(push) = Self::__PUSH {
             push(self, lua)?;
@@ -100,6 +105,7 @@ pub trait ToLua: Sized {
 
 impl ToLua for () {
     const __PUSH: Option<fn(Self, &State) -> Result<()>> = Some(|_, s| Ok(s.push_nil()));
+    const __SCALAR: Option<fn(Self) -> Value<'static>> = Some(|_| Value::Nil);
 }
 
 impl ToLua for &[u8] {
@@ -167,6 +173,33 @@ impl<'a> FromLua<'a> for OsString {
     }
 }
 
+/// The network addresses are represented as strings, such as `127.0.0.1:80`
+macro_rules! impl_net_addr {
+    ($($t:ty) *) => {
+        $(
+        #[cfg(feature = "std")]
+        impl ToLua for $t {
+            const __PUSH: Option<fn(Self, &State) -> Result<()>> = Some(|this, s: &State| {
+                s.push_string(&alloc::format!("{this}"));
+                Ok(())
+            });
+        }
+
+        #[cfg(feature = "std")]
+        impl FromLua<'_> for $t {
+            fn from_lua(_: &State, val: ValRef) -> Result<$t> {
+                let s = val.to_str().ok_or_else(|| Error::TypeNotMatch(val.type_of()))?;
+                s.parse().map_err(|err| {
+                    Error::convert(alloc::format!("invalid {} {s:?}: {err}", stringify!($t)))
+                })
+            }
+        }
+        )*
+    }
+}
+
+impl_net_addr!(std::net::IpAddr std::net::Ipv4Addr std::net::Ipv6Addr std::net::SocketAddr);
+
 impl ToLua for Value<'_> {
     const __PUSH: Option<fn(Self, &State) -> Result<()>> =
         Some(|this, s: &State| Ok(s.pushv(this)));
@@ -196,16 +229,26 @@ pub unsafe extern "C-unwind" fn __gc<T>(l: *mut lua_State) -> i32 {
 impl ToLua for f64 {
     const __PUSH: Option<fn(Self, &State) -> Result<()>> =
         Some(|this, s: &State| Ok(s.push_number(this)));
+    const __SCALAR: Option<fn(Self) -> Value<'static>> = Some(Value::Number);
 }
 
 impl ToLua for f32 {
     const __PUSH: Option<fn(Self, &State) -> Result<()>> =
         Some(|this, s: &State| Ok(s.push_number(this as _)));
+    const __SCALAR: Option<fn(Self) -> Value<'static>> = Some(|this| Value::Number(this as _));
 }
 
 impl ToLua for bool {
     const __PUSH: Option<fn(Self, &State) -> Result<()>> =
         Some(|this, s: &State| Ok(s.push_bool(this)));
+    const __SCALAR: Option<fn(Self) -> Value<'static>> = Some(Value::Bool);
+}
+
+impl ToLua for char {
+    const __PUSH: Option<fn(Self, &State) -> Result<()>> = Some(|this, s: &State| {
+        s.push_string(this.encode_utf8(&mut [0u8; 4]));
+        Ok(())
+    });
 }
 
 impl ToLua for CFunction {
@@ -228,6 +271,26 @@ impl<T: ToLua> ToLua for Vec<T> {
     }
 }
 
+impl<T: ToLua, const N: usize> ToLua for [T; N] {
+    fn to_lua<'a>(self, s: &'a State) -> Result<ValRef<'a>> {
+        s.new_val(IterVec(self.into_iter()))
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: ToLua> ToLua for VecDeque<T> {
+    fn to_lua<'a>(self, s: &'a State) -> Result<ValRef<'a>> {
+        s.new_val(IterVec(self.into_iter()))
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: ToLua> ToLua for LinkedList<T> {
+    fn to_lua<'a>(self, s: &'a State) -> Result<ValRef<'a>> {
+        s.new_val(IterVec(self.into_iter()))
+    }
+}
+
 #[cfg(feature = "std")]
 impl<K: ToLua, V: ToLua> ToLua for HashMap<K, V> {
     fn to_lua<'a>(self, s: &'a State) -> Result<ValRef<'a>> {
@@ -235,6 +298,26 @@ impl<K: ToLua, V: ToLua> ToLua for HashMap<K, V> {
     }
 }
 
+impl<K: ToLua, V: ToLua> ToLua for BTreeMap<K, V> {
+    fn to_lua<'a>(self, s: &'a State) -> Result<ValRef<'a>> {
+        s.new_val(crate::prelude::IterMap(self.into_iter()))
+    }
+}
+
+/// Set is represented as a table mapping each element to `true`
+#[cfg(feature = "std")]
+impl<T: ToLua> ToLua for HashSet<T> {
+    fn to_lua<'a>(self, s: &'a State) -> Result<ValRef<'a>> {
+        s.new_val(crate::prelude::IterMap(self.into_iter().map(|k| (k, true))))
+    }
+}
+
+impl<T: ToLua> ToLua for BTreeSet<T> {
+    fn to_lua<'a>(self, s: &'a State) -> Result<ValRef<'a>> {
+        s.new_val(crate::prelude::IterMap(self.into_iter().map(|k| (k, true))))
+    }
+}
+
 /// Trait for types that can be taken from the Lua stack
 ///
 /// For the reference types such as `&[u8]`, `&str`, the conversion will fail if `val` not an argument passed by lua.
@@ -253,7 +336,8 @@ pub(crate) fn check_from_lua<'a, T: FromLua<'a>>(lua: &'a State, i: Index) -> Re
     lua.from_index.set(i);
     T::from_lua(lua, lua.val(i)).map_err(|err| {
         Error::convert(alloc::format!(
-            "cast #{i}({}) failed, expect {}: {err:?}",
+            "{}cast #{i}({}) failed, expect {}: {err:?}",
+            lua.arg_error_location(),
             lua.type_of(i),
             T::TYPE_NAME
         ))
@@ -360,6 +444,34 @@ impl<'a, V: FromLua<'a> + 'static> FromLua<'a> for Vec<V> {
     }
 }
 
+impl<'a, V: FromLua<'a> + 'static, const N: usize> FromLua<'a> for [V; N] {
+    fn from_lua(s: &'a State, val: ValRef<'a>) -> Result<Self> {
+        let len = val.as_table().ok_or("").lua_result()?.raw_len();
+        if len != N {
+            return Err(Error::convert(alloc::format!(
+                "expect an array of length {N}, but got {len}"
+            )));
+        }
+        Vec::from_lua(s, val)?
+            .try_into()
+            .map_err(|_| Error::ConvertFailed)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, V: FromLua<'a> + 'static> FromLua<'a> for VecDeque<V> {
+    fn from_lua(s: &'a State, val: ValRef<'a>) -> Result<Self> {
+        Vec::from_lua(s, val).map(Into::into)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, V: FromLua<'a> + 'static> FromLua<'a> for LinkedList<V> {
+    fn from_lua(s: &'a State, val: ValRef<'a>) -> Result<Self> {
+        Vec::from_lua(s, val).map(|v| v.into_iter().collect())
+    }
+}
+
 #[cfg(feature = "std")]
 impl<'a, K: FromLua<'a> + Eq + Hash + 'static, V: FromLua<'a> + 'static> FromLua<'a>
     for HashMap<K, V>
@@ -376,6 +488,52 @@ impl<'a, K: FromLua<'a> + Eq + Hash + 'static, V: FromLua<'a> + 'static> FromLua
     }
 }
 
+impl<'a, K: FromLua<'a> + Ord + 'static, V: FromLua<'a> + 'static> FromLua<'a> for BTreeMap<K, V> {
+    fn from_lua(s: &'a State, val: ValRef<'a>) -> Result<Self> {
+        let t = val.as_table().ok_or("").lua_result()?;
+
+        let mut result = BTreeMap::new();
+        for (k, v) in t.iter()? {
+            result.insert(k.cast_into::<K>()?, v.cast_into::<V>()?);
+        }
+
+        Ok(result)
+    }
+}
+
+/// Collect the keys whose value is truthy
+#[cfg(feature = "std")]
+impl<'a, T: FromLua<'a> + Eq + Hash + 'static> FromLua<'a> for HashSet<T> {
+    fn from_lua(s: &'a State, val: ValRef<'a>) -> Result<Self> {
+        let t = val.as_table().ok_or("").lua_result()?;
+
+        let mut result = HashSet::new();
+        for (k, v) in t.iter()? {
+            if v.to_bool() {
+                result.insert(k.cast_into::<T>()?);
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+/// Collect the keys whose value is truthy
+impl<'a, T: FromLua<'a> + Ord + 'static> FromLua<'a> for BTreeSet<T> {
+    fn from_lua(s: &'a State, val: ValRef<'a>) -> Result<Self> {
+        let t = val.as_table().ok_or("").lua_result()?;
+
+        let mut result = BTreeSet::new();
+        for (k, v) in t.iter()? {
+            if v.to_bool() {
+                result.insert(k.cast_into::<T>()?);
+            }
+        }
+
+        Ok(result)
+    }
+}
+
 impl FromLua<'_> for f64 {
     #[inline(always)]
     fn from_lua(lua: &State, val: ValRef) -> Result<f64> {
@@ -400,6 +558,34 @@ impl FromLua<'_> for bool {
     }
 }
 
+/// Converted from a string of single character, or an integer as the code point
+impl FromLua<'_> for char {
+    fn from_lua(lua: &State, val: ValRef) -> Result<char> {
+        match val.type_of() {
+            Type::String => {
+                let s = val
+                    .to_str()
+                    .ok_or_else(|| Error::convert("invalid utf8 string"))?;
+                let mut chars = s.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Ok(c),
+                    _ => Err(Error::convert(alloc::format!(
+                        "expect a single character, got {s:?}"
+                    ))),
+                }
+            }
+            Type::Number if lua.is_integer(val.index) => {
+                let n = val.to_integer();
+                u32::try_from(n)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or_else(|| Error::convert(alloc::format!("invalid code point {n}")))
+            }
+            ty => Err(Error::TypeNotMatch(ty)),
+        }
+    }
+}
+
 impl<'a, T: FromLua<'a>> FromLua<'a> for Option<T> {
     #[inline(always)]
     fn from_lua(lua: &'a State, val: ValRef<'a>) -> Result<Option<T>> {
@@ -412,6 +598,7 @@ macro_rules! impl_integer {
         $(
         impl ToLua for $t {
             const __PUSH: Option<fn(Self, &State) -> Result<()>> = Some(|this, s: &State| Ok(s.push_integer(this as _)));
+            const __SCALAR: Option<fn(Self) -> Value<'static>> = Some(|this| Value::Integer(this as _));
         }
 
         impl FromLua<'_> for $t {
@@ -437,12 +624,101 @@ macro_rules! impl_integer {
                 }
             }
         }
+
+        impl FromLua<'_> for Checked<$t> {
+            fn from_lua(lua: &State, val: ValRef) -> Result<Checked<$t>> {
+                let i = val.index;
+                if lua.is_integer(i) {
+                    let n = lua.to_integer(i);
+                    <$t>::try_from(n).map(Self).map_err(|_| {
+                        Error::convert(alloc::format!("{n} is out of range of {}", stringify!($t)))
+                    })
+                } else if lua.is_number(i) {
+                    let n = lua.to_number(i);
+                    if n.fract() != 0.0 || !n.is_finite() {
+                        Err(Error::convert(alloc::format!("{n} has no integer representation")))
+                    } else if n < <$t>::MIN as lua_Number || n >= <$t>::MAX as lua_Number + 1.0 {
+                        Err(Error::convert(alloc::format!("{n} is out of range of {}", stringify!($t))))
+                    } else {
+                        Ok(Self(n as $t))
+                    }
+                } else {
+                    Err(Error::TypeNotMatch(val.type_of()))
+                }
+            }
+        }
         )*
     }
 }
 
 impl_integer!(isize usize u8 u16 u32 u64 i8 i16 i32 i64);
 
+/// 128-bit integers beyond the range of [`lua_Integer`] are converted to decimal strings,
+/// and converted back from the integers, integral floats or decimal strings.
+/// Use [`Checked`] to raise an error instead of converting to string
+macro_rules! impl_wide_integer {
+    ($($t:ty) *) => {
+        $(
+        impl ToLua for $t {
+            const __PUSH: Option<fn(Self, &State) -> Result<()>> = Some(|this, s: &State| {
+                match lua_Integer::try_from(this) {
+                    Ok(i) => s.push_integer(i),
+                    Err(_) => s.push_string(&alloc::format!("{this}")),
+                }
+                Ok(())
+            });
+        }
+
+        impl ToLua for Checked<$t> {
+            const __PUSH: Option<fn(Self, &State) -> Result<()>> = Some(|this, s: &State| {
+                let i = lua_Integer::try_from(this.0).map_err(|_| {
+                    Error::convert(alloc::format!("{} is out of range of lua integer", this.0))
+                })?;
+                s.push_integer(i);
+                Ok(())
+            });
+        }
+
+        impl FromLua<'_> for $t {
+            fn from_lua(lua: &State, val: ValRef) -> Result<$t> {
+                let i = val.index;
+                if lua.is_integer(i) {
+                    let n = lua.to_integer(i);
+                    <$t>::try_from(n).map_err(|_| {
+                        Error::convert(alloc::format!("{n} is out of range of {}", stringify!($t)))
+                    })
+                } else if val.type_of() == Type::String {
+                    let s = val.to_str().unwrap_or_default();
+                    s.trim().parse().map_err(|_| {
+                        Error::convert(alloc::format!("{s:?} is not a valid {}", stringify!($t)))
+                    })
+                } else if lua.is_number(i) {
+                    let n = lua.to_number(i);
+                    if n.fract() != 0.0 || !n.is_finite() || n < <$t>::MIN as lua_Number || n >= <$t>::MAX as lua_Number {
+                        Err(Error::convert(alloc::format!("{n} is not a valid {}", stringify!($t))))
+                    } else {
+                        Ok(n as $t)
+                    }
+                } else {
+                    Err(Error::TypeNotMatch(val.type_of()))
+                }
+            }
+        }
+
+        impl FromLua<'_> for Checked<$t> {
+            fn from_lua(lua: &State, val: ValRef) -> Result<Checked<$t>> {
+                let Checked(n) = Checked::<lua_Integer>::from_lua(lua, val)?;
+                <$t>::try_from(n).map(Self).map_err(|_| {
+                    Error::convert(alloc::format!("{n} is out of range of {}", stringify!($t)))
+                })
+            }
+        }
+        )*
+    }
+}
+
+impl_wide_integer!(i128 u128);
+
 /// Types which can be pushed onto lua stack,
 /// as returned multiple values to lua function,
 /// or as passed multiple arguments to lua function
@@ -506,6 +782,25 @@ impl<T: ToLuaMulti, E: Debug + Send + Sync + 'static> ToLuaMulti for core::resul
     }
 }
 
+#[cfg(feature = "either")]
+impl<A: ToLuaMulti, B: ToLuaMulti> ToLuaMulti for either::Either<A, B> {
+    #[inline(always)]
+    fn value_count(&self) -> Option<usize> {
+        match self {
+            Self::Left(a) => a.value_count(),
+            Self::Right(b) => b.value_count(),
+        }
+    }
+
+    #[inline(always)]
+    fn push_multi(self, s: &State) -> Result<usize> {
+        match self {
+            Self::Left(a) => a.push_multi(s),
+            Self::Right(b) => b.push_multi(s),
+        }
+    }
+}
+
 macro_rules! impl_method {
     ($(($x:ident, $i:tt)) *) => (
         // For normal function
@@ -717,6 +1012,10 @@ impl_tuple!((A, 0)(B, 1)(C, 2)(D, 3)(E, 4)(F, 5)(G, 6)(H, 7)(I, 8)(J, 9));
 impl_tuple!((A, 0)(B, 1)(C, 2)(D, 3)(E, 4)(F, 5)(G, 6)(H, 7)(I, 8)(J, 9)(K, 10));
 impl_tuple!((A, 0)(B, 1)(C, 2)(D, 3)(E, 4)(F, 5)(G, 6)(H, 7)(I, 8)(J, 9)(K, 10)(L, 11));
 
+/// Dynamic function signature for [`State::new_boxed_function`]
+pub type BoxedFunction =
+    Box<dyn for<'l> Fn(&'l State, MultiValue<'l>) -> Result<MultiValue<'l>> + 'static>;
+
 macro_rules! impl_closure {
     ($name:ident ($($x:ident:$i:literal)*)) => (
         #[inline(always)]
@@ -798,6 +1097,43 @@ impl State {
         )
     }
 
+    /// Bind a rust function(closure) with uniform argument types, which doesn't take the `&State`,
+    /// for the functions taking it, see [`Self::new_function`]
+    ///
+    /// ```rust
+    /// # use ezlua::prelude::*;
+    /// # fn main() -> LuaResult<()> {
+    /// let lua = Lua::with_open_libs();
+    /// lua.global().set("inc", lua.new_fn(|x: i64| x + 1)?)?;
+    /// lua.do_string("assert(inc(1) == 2)", None)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[inline(always)]
+    pub fn new_fn<
+        'l,
+        ARGS: FromLuaMulti<'l>,
+        RET: ToLuaMulti + 'l,
+        F: Fn(ARGS) -> RET + 'static,
+    >(
+        &'l self,
+        fun: F,
+    ) -> Result<Function<'_>> {
+        self.bind_closure(
+            move |s: &'l State| Result::Ok(fun(ARGS::from_lua_multi(s, 1)?)),
+            0,
+        )
+    }
+
+    /// Bind a boxed rust function, all the boxed functions share a same wrapper,
+    /// which reduces the code size when creating many functions dynamically
+    pub fn new_boxed_function(&self, fun: BoxedFunction) -> Result<Function<'_>> {
+        self.bind_closure(
+            move |s: &State| -> Result<MultiValue> { fun(s, check_from_lua(s, 1)?) },
+            0,
+        )
+    }
+
     /// Bind a rust function(closure) with flexible argument types
     #[inline(always)]
     pub fn new_closure<'l, A: 'l, R: 'l, F: LuaMethod<'l, (), A, R> + 'static>(
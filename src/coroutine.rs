@@ -1,4 +1,4 @@
-use core::ptr::null_mut;
+use core::{ffi::c_int, ptr::null_mut};
 
 use crate::{
     convert::*,
@@ -6,6 +6,7 @@ use crate::{
     ffi::*,
     lua::ArcLuaInner,
     luaapi::{ThreadStatus, Type, UnsafeLuaApi, NOREF},
+    marker::{MultiRet, MultiValue, Pushed},
     prelude::Reference,
     state::State,
     value::{Function, ValRef},
@@ -133,3 +134,194 @@ impl Drop for CoroutineWithRef {
         }
     }
 }
+
+struct Continuation<K> {
+    base: Index,
+    k: Option<K>,
+}
+
+/// Push the continuation as an userdata with `__gc` into current frame,
+/// so it will be dropped along with the thread even if the coroutine is never resumed
+unsafe fn push_continuation<K>(l: *mut lua_State, base: Index, k: K) -> *mut Continuation<K> {
+    let p =
+        lua_newuserdatauv(l, core::mem::size_of::<Continuation<K>>(), 0) as *mut Continuation<K>;
+    p.write(Continuation { base, k: Some(k) });
+    lua_createtable(l, 0, 1);
+    lua_pushcfunction(l, Some(__gc::<Continuation<K>>));
+    lua_setfield(l, -2, crate::cstr!("__gc").as_ptr());
+    lua_setmetatable(l, -2);
+    p
+}
+
+unsafe extern "C-unwind" fn continue_with<
+    R: ToLuaMulti,
+    K: for<'s> FnOnce(&'s State, Result<MultiValue<'s>>) -> R,
+>(
+    l: *mut lua_State,
+    status: c_int,
+    ctx: lua_KContext,
+) -> c_int {
+    let cont = &mut *(ctx as *mut Continuation<K>);
+    let base = cont.base;
+    let k = cont.k.take().expect("continuation called twice");
+    let status = match status {
+        LUA_OK | LUA_YIELD => Ok(()),
+        code => State::from_raw_state(l).statuscode_to_error_and_pop(code),
+    };
+    // the rust frame was unwound, drop its slots and the continuation, keep only the results
+    lua_rotate(l, 1, -(base + 1));
+    lua_settop(l, -(base + 1) - 1);
+
+    let state = State::from_raw_state(l);
+    let results = status.map(|_| {
+        MultiRet(
+            (1..=state.get_top())
+                .map(|i| state.val(i).into_value())
+                .collect(),
+        )
+    });
+    let result = k(&state, results);
+    (match result.push_multi(&state) {
+        Ok(n) => n,
+        Err(err) => state.raise_error(err),
+    }) as _
+}
+
+unsafe fn yieldk<R: ToLuaMulti, K: for<'s> FnOnce(&'s State, Result<MultiValue<'s>>) -> R>(
+    l: *mut lua_State,
+    n: c_int,
+    ctx: *mut Continuation<K>,
+) {
+    lua_yieldk(l, n, ctx as _, Some(continue_with::<R, K>));
+}
+
+impl State {
+    /// Yield values from current rust function to the resumer of the coroutine,
+    /// the values passed to the next resume will be returned as the results of the rust function.
+    ///
+    /// The rust frames are unwound without dropping, so avoid holding values which need be dropped
+    #[inline(always)]
+    pub fn yield_with<A: ToLuaMulti>(&self, args: A) -> ! {
+        self.yield_with_k(args, |s| Pushed(s.get_top() as _))
+    }
+
+    /// Like [`State::yield_with`], but the values returned by `k` become the results of the rust function,
+    /// `k` will be called with the values passed to the next resume as its arguments
+    pub fn yield_with_k<A: ToLuaMulti, R: ToLuaMulti, K: FnOnce(&State) -> R + 'static>(
+        &self,
+        args: A,
+        k: K,
+    ) -> ! {
+        let l = self.state;
+        let n = match self
+            .check_stack(args.value_count().unwrap_or(10) as i32 + 3)
+            .and_then(|_| self.push_multi(args))
+        {
+            Ok(n) => n as c_int,
+            Err(err) => unsafe { State::from_raw_state(l).raise_error(err) },
+        };
+        unsafe {
+            // only keep the continuation and the yielded values in current frame
+            let ctx = push_continuation(l, 0, move |s: &State, _: Result<MultiValue>| k(s));
+            lua_rotate(l, 1, n + 1);
+            lua_settop(l, n + 1);
+            lua_rotate(l, 1, 1);
+            yieldk::<R, _>(l, n, ctx);
+        }
+        unreachable!("lua_yieldk returned")
+    }
+
+    /// Call `func` in a new coroutine, each time it yields, `on_yield` is called with the yielded values,
+    /// and the coroutine is resumed with the values returned by `on_yield`, until the function returns
+    pub fn pcall_on_yield<'a, R: FromLuaMulti<'a>>(
+        &'a self,
+        func: Function<'a>,
+        args: impl ToLuaMulti,
+        mut on_yield: impl for<'c> FnMut(&'c State, MultiValue<'c>) -> Result<MultiValue<'c>>,
+    ) -> Result<R> {
+        let guard = self.stack_guard();
+        self.check_stack(2)?;
+        // keep the thread on the stack to prevent it from being collected
+        let co = self.new_thread();
+        let thread = unsafe { State::from_raw_state(co) };
+        thread.check_stack(args.value_count().unwrap_or(10) as i32 + 1)?;
+        thread.pushvalref(&func.0);
+        let mut nargs = thread.push_multi(args)? as c_int;
+
+        loop {
+            let mut nres = 0;
+            match thread.resume(self.state, nargs, &mut nres) {
+                ThreadStatus::Yield => {
+                    // move the yielded values out, the stack of coroutine is rewritten by the next resume
+                    self.check_stack(nres)?;
+                    thread.xmove(self.state, nres);
+                    let top = self.get_top();
+                    let caller = unsafe { State::from_raw_state(self.state) };
+                    let values = (top - nres + 1..=top)
+                        .map(|i| caller.val(i).into_value())
+                        .collect();
+                    let results = on_yield(&caller, MultiRet(values))?;
+                    caller.check_stack(results.len() as c_int)?;
+                    nargs = caller.push_multi(results)? as c_int;
+                    thread.check_stack(nargs)?;
+                    caller.xmove(co, nargs);
+                    self.set_top(guard.top() + 1);
+                }
+                ThreadStatus::Ok => {
+                    self.check_stack(nres)?;
+                    thread.xmove(self.state, nres);
+                    let result_base = guard.top() + 2;
+                    return self.to_multi_balance(guard, result_base);
+                }
+                err => {
+                    let err = thread.status_to_error(err).unwrap_err();
+                    self.record_error(&err);
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    /// Call a function which is allowed to yield across current rust function,
+    /// the call results are passed to `k`, whether it yielded or not.
+    ///
+    /// This should be the tail expression of the rust function, the values returned by `k` become its results
+    pub fn pcall_k<
+        F: ToLua,
+        A: ToLuaMulti,
+        R: ToLuaMulti,
+        K: for<'s> FnOnce(&'s State, Result<MultiValue<'s>>) -> R + 'static,
+    >(
+        &self,
+        func: F,
+        args: A,
+        k: K,
+    ) -> Result<R> {
+        self.check_stack(args.value_count().unwrap_or(10) as i32 + 4)?;
+        let guard = self.stack_guard();
+        let base = guard.top();
+        let ctx = unsafe { push_continuation(self.state, base, k) };
+        self.push(func)?;
+        let nargs = self.push_multi(args)? as c_int;
+        let status = unsafe {
+            lua_pcallk(
+                self.state,
+                nargs,
+                LUA_MULTRET,
+                0,
+                ctx as _,
+                Some(continue_with::<R, K>),
+            )
+        };
+        // the continuation is not called when no yield occurs
+        let k = unsafe { (*ctx).k.take() }.expect("continuation called twice");
+        let results = match self.statuscode_to_error(status) {
+            Ok(()) => self.to_multi_balance(guard, base + 2),
+            Err(err) => {
+                drop(guard);
+                Err(err)
+            }
+        };
+        Ok(k(self, results))
+    }
+}
@@ -9,8 +9,11 @@ use crate::luaapi::Type;
 
 pub type Result<T> = core::result::Result<T, Error>;
 
+/// The error type of this crate, it's `non_exhaustive` to allow adding new kinds of error
 #[derive(From)]
+#[non_exhaustive]
 pub enum Error {
+    /// Runtime error, the traceback is appended to the message in protected call, see [`Error::traceback`]
     Runtime(String),
     #[from(ignore)]
     Memory(String),
@@ -19,11 +22,20 @@ pub enum Error {
     #[from(ignore)]
     Gc(String),
     Yield,
+    /// Failed to grow the lua stack for `requested` more slots, see [`Error::is_stack_overflow`]
+    #[from(ignore)]
+    StackOverflow {
+        requested: i32,
+    },
     #[from(ignore)]
     Convert(String),
     ConvertFailed,
     Else(Box<dyn Debug + Send + Sync>),
     TypeNotMatch(Type),
+    /// Error from the outside of lua, which is returned by [`std::error::Error::source`]
+    #[cfg(feature = "std")]
+    #[from(ignore)]
+    External(Box<dyn std::error::Error + Send + Sync>),
 }
 
 impl Debug for Error {
@@ -34,10 +46,15 @@ impl Debug for Error {
             Self::Syntax(arg0) => f.debug_tuple("Syntax").field(arg0).finish(),
             Self::Gc(arg0) => f.debug_tuple("Gc").field(arg0).finish(),
             Self::Yield => write!(f, "Yield"),
+            Self::StackOverflow { requested } => {
+                write!(f, "stack overflow (requested {requested} slots)")
+            }
             Self::Convert(arg0) => write!(f, "convert: {arg0}"),
             Self::ConvertFailed => write!(f, "ConvertFailed"),
             Self::Else(arg0) => f.debug_tuple("Else").field(arg0).finish(),
             Self::TypeNotMatch(arg0) => f.debug_tuple("TypeNotMatch").field(arg0).finish(),
+            #[cfg(feature = "std")]
+            Self::External(err) => write!(f, "{err}"),
         }
     }
 }
@@ -64,6 +81,63 @@ impl Error {
     pub fn runtime_debug<E: Debug>(err: E) -> Self {
         Self::runtime(format!("{err:?}"))
     }
+
+    /// Wrap an error from the outside of lua, keeping it as the source
+    #[cfg(feature = "std")]
+    pub fn external<E: std::error::Error + Send + Sync + 'static>(err: E) -> Self {
+        Self::External(Box::new(err))
+    }
+
+    /// Create a runtime error from the `Display` text of `err`, followed by its `source()` chain
+    #[cfg(feature = "std")]
+    pub fn runtime_display<E: std::error::Error + ?Sized>(err: &E) -> Self {
+        let mut msg = format!("{err}");
+        let mut source = err.source();
+        while let Some(err) = source {
+            msg += &format!(": {err}");
+            source = err.source();
+        }
+        Self::runtime(msg)
+    }
+
+    /// Whether the lua stack is exhausted, the slots held by the dropped values may be reclaimed by [`State::gc_collect`](crate::prelude::LuaState::gc_collect)
+    pub fn is_stack_overflow(&self) -> bool {
+        matches!(self, Self::StackOverflow { .. })
+    }
+
+    /// The lua traceback appended to the runtime error message in protected call
+    pub fn traceback(&self) -> Option<&str> {
+        match self {
+            Self::Runtime(msg) => split_traceback(msg).map(|(_, traceback)| traceback),
+            _ => None,
+        }
+    }
+
+    /// Copy of this error, the `Else` and `External` variants are converted to runtime errors by their text
+    pub(crate) fn duplicate(&self) -> Self {
+        match self {
+            Self::Runtime(s) => Self::Runtime(s.clone()),
+            Self::Memory(s) => Self::Memory(s.clone()),
+            Self::Syntax(s) => Self::Syntax(s.clone()),
+            Self::Gc(s) => Self::Gc(s.clone()),
+            Self::Yield => Self::Yield,
+            Self::StackOverflow { requested } => Self::StackOverflow {
+                requested: *requested,
+            },
+            Self::Convert(s) => Self::Convert(s.clone()),
+            Self::ConvertFailed => Self::ConvertFailed,
+            Self::Else(e) => Self::runtime_debug(e),
+            Self::TypeNotMatch(t) => Self::TypeNotMatch(*t),
+            #[cfg(feature = "std")]
+            Self::External(err) => Self::runtime(format!("{err}")),
+        }
+    }
+}
+
+/// Split the traceback appended by the message handler from the runtime error message
+fn split_traceback(msg: &str) -> Option<(&str, &str)> {
+    let i = msg.rfind("stack traceback:")?;
+    Some((msg[..i].trim_end_matches('\n'), &msg[i..]))
 }
 
 pub trait ToLuaResult<T, E> {
@@ -96,5 +170,62 @@ impl<T, E: Debug> ToLuaResult<T, E> for core::result::Result<T, E> {
     }
 }
 
+/// Error of a protected call with the traceback separated, see [`ValRef::pcall_traced`](crate::value::ValRef::pcall_traced)
+#[derive(Debug)]
+pub struct TracedError {
+    pub error: Error,
+    /// Traceback produced by the message handler, empty if it's not captured
+    pub traceback: String,
+}
+
+impl From<Error> for TracedError {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Runtime(msg) if split_traceback(&msg).is_some() => {
+                let (message, traceback) = split_traceback(&msg).unwrap();
+                Self {
+                    error: Error::Runtime(message.into()),
+                    traceback: traceback.into(),
+                }
+            }
+            error => Self {
+                error,
+                traceback: String::new(),
+            },
+        }
+    }
+}
+
+impl core::fmt::Display for TracedError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if self.traceback.is_empty() {
+            write!(f, "{}", self.error)
+        } else {
+            write!(f, "{}\n{}", self.error, self.traceback)
+        }
+    }
+}
+
 #[cfg(feature = "std")]
-impl std::error::Error for Error {}
+impl std::error::Error for TracedError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::External(err) => Some(&**err),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Self::external(err)
+    }
+}
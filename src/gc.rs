@@ -0,0 +1,144 @@
+use alloc::{boxed::Box, rc::Rc};
+use core::{cell::Cell, ffi::c_int};
+
+use crate::{
+    convert::__gc,
+    error::Result,
+    ffi::*,
+    lua::{AllocHook, AllocLayer},
+    luaapi::UnsafeLuaApi,
+    state::State,
+};
+
+/// Event reported to the gc observer, see [`State::set_gc_observer`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GcPhase {
+    /// A step driven by [`State::gc_step`] or [`State::gc_step_kbytes`] finished
+    Step,
+    /// A collection cycle completed
+    Cycle,
+}
+
+struct GcObserver {
+    callback: Box<dyn FnMut(GcPhase, usize)>,
+    used: Rc<Cell<usize>>,
+    counter: Option<Box<AllocLayer>>,
+    main: *mut lua_State,
+}
+
+impl GcObserver {
+    fn notify(&mut self, phase: GcPhase) {
+        (self.callback)(phase, self.used.get());
+    }
+}
+
+impl Drop for GcObserver {
+    fn drop(&mut self) {
+        if let Some(counter) = self.counter.take() {
+            counter.remove(self.main);
+        }
+    }
+}
+
+/// Counts the memory in use in the allocator chain, because `lua_gc` is not available inside the finalizers since lua 5.4.4
+struct MemoryCounter(Rc<Cell<usize>>);
+
+impl AllocHook for MemoryCounter {
+    fn changed(&mut self, old: usize, new: usize) {
+        self.0.set((self.0.get() + new).saturating_sub(old));
+    }
+}
+
+static GC_OBSERVER: u8 = 0;
+
+/// Finalizer of the sentinel table, which becomes garbage right after created,
+/// so it is finalized at the end of each collection cycle, and then re-armed for the next one
+unsafe extern "C-unwind" fn sentinel_gc(l: *mut lua_State) -> c_int {
+    let s = State::from_raw_state(l);
+    if s.check_stack(4).is_err() {
+        return 0;
+    }
+    s.raw_getp(LUA_REGISTRYINDEX, &GC_OBSERVER);
+    s.raw_geti(1, 1);
+    // the observer was removed or replaced
+    if s.is_nil(2) || !s.raw_equal(2, 3) {
+        return 0;
+    }
+
+    s.create_table(1, 0);
+    s.push_value(2);
+    s.raw_seti(-2, 1);
+    s.get_metatable(1);
+    s.set_metatable(-2);
+    s.pop(1);
+
+    if let Some(observer) = s.to_userdata_typed::<GcObserver>(2) {
+        observer.notify(GcPhase::Cycle);
+    }
+    0
+}
+
+impl State {
+    /// Observe the garbage collection, `callback` is called with the memory in use (in bytes)
+    /// when a collection cycle completed, or a step driven by [`Self::gc_step`] finished.
+    ///
+    /// Lua has no gc callback, the cycle completion is detected by the finalizer of a sentinel object,
+    /// and the memory in use is counted by wrapping the allocator until the observer collected
+    pub fn set_gc_observer(&self, callback: impl FnMut(GcPhase, usize) + 'static) -> Result<()> {
+        self.check_stack(4)?;
+        let _guard = self.stack_guard();
+
+        let main = unsafe {
+            self.raw_geti(LUA_REGISTRYINDEX, LUA_RIDX_MAINTHREAD);
+            let main = lua_tothread(self.state, -1);
+            self.pop(1);
+            main
+        };
+        let used = Rc::new(Cell::new(self.used_memory()));
+        let observer = self.push_userdatauv(
+            GcObserver {
+                callback: Box::new(callback),
+                used: used.clone(),
+                counter: None,
+                main,
+            },
+            0,
+        )?;
+        observer.counter = Some(AllocLayer::install(main, MemoryCounter(used)));
+        self.create_table(0, 1);
+        self.push_fn(Some(__gc::<GcObserver>));
+        self.set_field(-2, crate::cstr!("__gc"));
+        self.set_metatable(-2);
+        self.push_value(-1);
+        self.raw_setp(LUA_REGISTRYINDEX, &GC_OBSERVER);
+
+        self.create_table(1, 0);
+        self.push_value(-2);
+        self.raw_seti(-2, 1);
+        self.create_table(0, 1);
+        self.push_fn(Some(sentinel_gc));
+        self.set_field(-2, crate::cstr!("__gc"));
+        self.set_metatable(-2);
+
+        Ok(())
+    }
+
+    /// Remove the gc observer
+    pub fn remove_gc_observer(&self) {
+        if self.check_stack(1).is_ok() {
+            self.push_nil();
+            self.raw_setp(LUA_REGISTRYINDEX, &GC_OBSERVER);
+        }
+    }
+
+    pub(crate) fn notify_gc_observer(&self, phase: GcPhase) {
+        if self.check_stack(1).is_err() {
+            return;
+        }
+        self.raw_getp(LUA_REGISTRYINDEX, &GC_OBSERVER);
+        if let Some(observer) = unsafe { self.to_userdata_typed::<GcObserver>(-1) } {
+            observer.notify(phase);
+        }
+        self.pop(1);
+    }
+}
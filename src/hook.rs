@@ -0,0 +1,266 @@
+use alloc::{boxed::Box, format, string::String, vec::Vec};
+use core::{cell::RefCell, ffi::c_int};
+
+#[cfg(feature = "std")]
+use crate::luaapi::HookMask;
+use crate::{
+    convert::{__gc, FromLuaMulti, ToLuaMulti},
+    error::{Error, Result},
+    ffi::*,
+    lua::{AllocLayer, MemoryLimiter},
+    luaapi::UnsafeLuaApi,
+    state::State,
+    userdata::UserData,
+    value::{Function, LuaUserData, ValRef},
+};
+
+/// What to do after a hook callback returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookAction {
+    /// Continue the execution
+    Continue,
+    /// Interrupt the execution by raising an error
+    Break,
+}
+
+/// Limits applied to a single call, see [`State::call_with_budget`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Budget {
+    /// Max count of the lua instructions can be executed
+    pub instructions: Option<u32>,
+    /// Max bytes of the memory can be allocated additionally
+    pub memory: Option<usize>,
+}
+
+/// Samples collected by [`State::start_profiler`]
+#[derive(Default)]
+struct ProfileSamples(RefCell<Vec<Vec<String>>>);
+
+impl UserData for ProfileSamples {
+    const TYPE_NAME: &'static str = "ProfileSamples";
+}
+
+static PROFILE_KEY: u8 = 0;
+
+pub(crate) type HookFn = Box<dyn FnMut(&State, &mut lua_Debug) -> Result<()>>;
+
+struct HookData {
+    callback: HookFn,
+    running: bool,
+}
+
+static HOOK_KEY: u8 = 0;
+
+unsafe extern "C-unwind" fn hook_dispatch(l: *mut lua_State, ar: *mut lua_Debug) {
+    let state = State::from_raw_state(l);
+    if state.check_stack(2).is_err() {
+        return;
+    }
+    // keep the hook data on the stack while calling, in case it is replaced by the callback
+    state.raw_getp(LUA_REGISTRYINDEX, &HOOK_KEY);
+    let result = match state.to_userdata_typed::<HookData>(-1) {
+        Some(hook) if !hook.running => {
+            hook.running = true;
+            let result = (hook.callback)(&state, &mut *ar);
+            hook.running = false;
+            result
+        }
+        _ => Ok(()),
+    };
+    state.pop(1);
+    if let Err(err) = result {
+        state.raise_error(err)
+    }
+}
+
+impl State {
+    /// Install the hook callback for current thread and the coroutines created after this,
+    /// the previous hook callback will be replaced
+    pub(crate) fn set_hook_fn(&self, mask: c_int, count: c_int, callback: HookFn) -> Result<()> {
+        self.check_stack(3)?;
+        let _guard = self.stack_guard();
+        self.push_userdatauv(
+            HookData {
+                callback,
+                running: false,
+            },
+            0,
+        )?;
+        let mt = self.new_table_with_size(0, 1)?;
+        mt.set("__gc", __gc::<HookData> as CFunction)?;
+        mt.0.ensure_top();
+        self.set_metatable(-2);
+        self.raw_setp(LUA_REGISTRYINDEX, &HOOK_KEY);
+        unsafe { lua_sethook(self.state, Some(hook_dispatch), mask, count) }
+
+        Ok(())
+    }
+
+    /// Install the debug hook `callback` for the events of `mask`, `count` is the instruction interval of [`HookMask::MASKCOUNT`].
+    /// The error returned from `callback` is raised after it returns, and the previous hook is replaced
+    #[cfg(feature = "std")]
+    pub fn set_hook(
+        &self,
+        mask: HookMask,
+        count: c_int,
+        mut callback: impl FnMut(&State, &lua_Debug) -> Result<()> + 'static,
+    ) -> Result<()> {
+        self.set_hook_fn(mask.bits(), count, Box::new(move |s, ar| callback(s, ar)))
+    }
+
+    /// Raise an error "instruction limit exceeded" after `n` lua instructions executed,
+    /// the limit is kept until [`Self::clear_instruction_limit`], and it replaces the previous hook
+    pub fn set_instruction_limit(&self, n: u64) -> Result<()> {
+        let step = n.clamp(1, c_int::MAX as u64);
+        let mut remaining = n.max(1);
+        self.set_hook_fn(
+            LUA_MASKCOUNT,
+            step as c_int,
+            Box::new(move |s, _| {
+                remaining = remaining.saturating_sub(step);
+                if remaining == 0 {
+                    return Err(Error::runtime("instruction limit exceeded"));
+                }
+                if remaining < step {
+                    unsafe {
+                        lua_sethook(
+                            s.state,
+                            Some(hook_dispatch),
+                            LUA_MASKCOUNT,
+                            remaining as c_int,
+                        )
+                    }
+                }
+                Ok(())
+            }),
+        )
+    }
+
+    /// Remove the limit set by [`Self::set_instruction_limit`]
+    pub fn clear_instruction_limit(&self) {
+        self.remove_hook()
+    }
+
+    /// Start sampling the lua call stack every `interval` instructions, the samples are taken by [`Self::take_profile`].
+    /// It replaces the previous hook, use [`Self::remove_hook`] to stop sampling
+    pub fn start_profiler(&self, interval: u32) -> Result<()> {
+        self.registry()
+            .setp(&PROFILE_KEY, self.new_userdata(ProfileSamples::default())?)?;
+        self.set_hook_fn(
+            LUA_MASKCOUNT,
+            interval.clamp(1, c_int::MAX as u32) as c_int,
+            Box::new(|s, _| {
+                let stack = s
+                    .frames(0, true)
+                    .iter()
+                    .rev()
+                    .map(|frame| format!("{}:{}", frame.source, frame.line))
+                    .collect::<Vec<_>>();
+                if stack.is_empty() {
+                    return Ok(());
+                }
+                if let Ok(samples) = LuaUserData::try_from(s.registry().getp(&PROFILE_KEY)?) {
+                    if let Some(samples) = samples.userdata_ref::<ProfileSamples>() {
+                        samples.0.borrow_mut().push(stack);
+                    }
+                }
+                Ok(())
+            }),
+        )
+    }
+
+    /// Take the samples collected by [`Self::start_profiler`], each sample is a call stack from the outermost frame
+    /// to the innermost, formatted as `source:line`, which can be joined by `;` as the collapsed stack of flamegraph
+    pub fn take_profile(&self) -> Vec<Vec<String>> {
+        self.registry()
+            .getp(&PROFILE_KEY)
+            .ok()
+            .and_then(|samples| LuaUserData::try_from(samples).ok())
+            .and_then(|samples| {
+                samples
+                    .userdata_ref::<ProfileSamples>()
+                    .map(|samples| samples.0.take())
+            })
+            .unwrap_or_default()
+    }
+
+    /// Remove the hook of current thread
+    pub fn remove_hook(&self) {
+        unsafe { lua_sethook(self.state, None, 0, 0) }
+        if self.check_stack(1).is_ok() {
+            self.push_nil();
+            self.raw_setp(LUA_REGISTRYINDEX, &HOOK_KEY);
+        }
+    }
+
+    /// Call `callback` with the source name and line number before executing each new line of lua code,
+    /// returning [`HookAction::Break`] raises an error which can be caught by the caller
+    pub fn on_line(
+        &self,
+        mut callback: impl FnMut(&State, &str, u32) -> HookAction + 'static,
+    ) -> Result<()> {
+        self.set_hook_fn(
+            LUA_MASKLINE,
+            0,
+            Box::new(move |s, ar| {
+                s.get_info(crate::cstr!("S"), ar);
+                let source = ar.short_src();
+                let line = ar.currentline as u32;
+                match callback(s, &source, line) {
+                    HookAction::Continue => Ok(()),
+                    HookAction::Break => Err(Error::runtime(format!("break at {source}:{line}"))),
+                }
+            }),
+        )
+    }
+
+    /// Call `func` with the limits of `budget`, the previous hook and allocator are restored after the call
+    pub fn call_with_budget<'a, R: FromLuaMulti<'a>>(
+        &'a self,
+        func: Function<'a>,
+        args: impl ToLuaMulti,
+        budget: Budget,
+    ) -> Result<R> {
+        let registry = self.registry();
+        let mut guard = BudgetGuard {
+            state: self,
+            hook: unsafe { lua_gethook(self.state) },
+            mask: unsafe { lua_gethookmask(self.state) },
+            count: unsafe { lua_gethookcount(self.state) },
+            data: registry.getp(&HOOK_KEY)?,
+            limiter: None,
+        };
+        if let Some(count) = budget.instructions {
+            self.set_hook_fn(
+                LUA_MASKCOUNT,
+                count.clamp(1, c_int::MAX as u32) as c_int,
+                Box::new(|_, _| Err(Error::runtime("instruction budget exceeded"))),
+            )?;
+        }
+        guard.limiter = budget
+            .memory
+            .map(|limit| AllocLayer::install(self.state, MemoryLimiter { used: 0, limit }));
+        let result = func.pcall(args);
+        drop(guard);
+        result
+    }
+}
+
+struct BudgetGuard<'a> {
+    state: &'a State,
+    hook: Option<lua_Hook>,
+    mask: c_int,
+    count: c_int,
+    data: ValRef<'a>,
+    limiter: Option<Box<AllocLayer>>,
+}
+
+impl Drop for BudgetGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(limiter) = self.limiter.take() {
+            limiter.remove(self.state.state);
+        }
+        let _ = self.state.registry().setp(&HOOK_KEY, &self.data);
+        unsafe { lua_sethook(self.state.state, self.hook, self.mask, self.count) }
+    }
+}
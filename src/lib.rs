@@ -55,6 +55,9 @@ pub mod value;
 #[cfg(feature = "async")]
 mod r#async;
 mod coroutine;
+mod gc;
+mod hook;
+mod scope;
 mod state;
 
 #[cfg(feature = "thread")]
@@ -1,8 +1,12 @@
 //! Implementation to lua_lock/lua_unlock, for multi-thread purpose
 
-use crate::ffi::{lua_State, lua_getextraspace};
+use crate::{
+    ffi::{lua_State, lua_getextraspace},
+    state::ExtraSpace,
+};
 
 use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, Ordering};
 #[cfg(feature = "parking_lot")]
 use parking_lot::{Mutex, MutexGuard};
 #[cfg(not(feature = "parking_lot"))]
@@ -12,6 +16,7 @@ use std::sync::{Mutex, MutexGuard};
 pub struct Extra {
     mutex: Mutex<()>,
     guard: Option<MutexGuard<'static, ()>>,
+    pub(crate) space: ExtraSpace,
 }
 
 #[inline(always)]
@@ -33,12 +38,22 @@ unsafe extern "C-unwind" fn ezlua_unlock(l: *mut lua_State) {
     get_extra(l).guard.take();
 }
 
+/// Whether the lua library is built with these hooks, otherwise the extra space is not occupied
+static HOOKED: AtomicBool = AtomicBool::new(false);
+
+#[inline(always)]
+pub(crate) fn is_hooked() -> bool {
+    HOOKED.load(Ordering::Relaxed)
+}
+
 #[no_mangle]
 unsafe extern "C-unwind" fn ezlua_userstateopen(l: *mut lua_State) {
     let extra = Box::new(Extra {
         mutex: Mutex::new(()),
         guard: None,
+        space: ExtraSpace::new(),
     });
+    HOOKED.store(true, Ordering::Relaxed);
     *core::mem::transmute::<_, *mut *mut Extra>(lua_getextraspace(l)) = Box::into_raw(extra);
 }
 
@@ -1,5 +1,18 @@
-use crate::{luaapi::UnsafeLuaApi, state::State, value::Value};
-use alloc::sync::Arc;
+#[cfg(feature = "bitflags")]
+use crate::state::StdLibSet;
+use crate::{
+    error::*,
+    ffi::*,
+    luaapi::UnsafeLuaApi,
+    state::{ExtraSpace, State},
+    value::Value,
+};
+use alloc::{boxed::Box, sync::Arc};
+use core::{
+    any::Any,
+    cell::{Cell, RefCell},
+    ffi::{c_char, c_int, c_void, CStr},
+};
 
 pub(crate) type ArcLuaInner = Arc<LuaInner>;
 
@@ -14,9 +27,312 @@ impl core::ops::Deref for Lua {
     }
 }
 
+/// Custom memory allocator for a lua state, see [`Lua::with_allocator`]
+///
+/// Every block returned to lua must be aligned as `malloc` does
+pub trait LuaAllocator {
+    /// Allocate a new block of `size` bytes, return null if failed
+    unsafe fn alloc(&self, size: usize) -> *mut c_void;
+
+    /// Resize a block allocated by this allocator, return null if failed
+    unsafe fn realloc(&self, ptr: *mut c_void, old_size: usize, new_size: usize) -> *mut c_void;
+
+    /// Release a block allocated by this allocator
+    unsafe fn free(&self, ptr: *mut c_void, size: usize);
+}
+
+/// The allocator based on libc `malloc`, which is the same as `luaL_newstate` uses
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemAllocator;
+
+impl LuaAllocator for SystemAllocator {
+    #[inline(always)]
+    unsafe fn alloc(&self, size: usize) -> *mut c_void {
+        libc::malloc(size)
+    }
+
+    #[inline(always)]
+    unsafe fn realloc(&self, ptr: *mut c_void, _old_size: usize, new_size: usize) -> *mut c_void {
+        libc::realloc(ptr, new_size)
+    }
+
+    #[inline(always)]
+    unsafe fn free(&self, ptr: *mut c_void, _size: usize) {
+        libc::free(ptr)
+    }
+}
+
+unsafe extern "C-unwind" fn allocator_wrapper<A: LuaAllocator>(
+    ud: *mut c_void,
+    ptr: *mut c_void,
+    osize: usize,
+    nsize: usize,
+) -> *mut c_void {
+    let alloc = &*(ud as *const A);
+    if nsize == 0 {
+        if !ptr.is_null() {
+            alloc.free(ptr, osize);
+        }
+        core::ptr::null_mut()
+    } else if ptr.is_null() {
+        // when ptr is null, osize encodes the kind of object being allocated
+        alloc.alloc(nsize)
+    } else {
+        alloc.realloc(ptr, osize, nsize)
+    }
+}
+
+/// The system allocator failing the allocations beyond `limit` bytes, see [`Lua::with_memory_limit`]
+struct LimitedAllocator {
+    used: Cell<usize>,
+    limit: usize,
+}
+
+impl LuaAllocator for LimitedAllocator {
+    unsafe fn alloc(&self, size: usize) -> *mut c_void {
+        if self.used.get() + size > self.limit {
+            return core::ptr::null_mut();
+        }
+        let result = SystemAllocator.alloc(size);
+        if !result.is_null() {
+            self.used.set(self.used.get() + size);
+        }
+        result
+    }
+
+    unsafe fn realloc(&self, ptr: *mut c_void, old_size: usize, new_size: usize) -> *mut c_void {
+        if new_size > old_size && self.used.get() + (new_size - old_size) > self.limit {
+            return core::ptr::null_mut();
+        }
+        let result = SystemAllocator.realloc(ptr, old_size, new_size);
+        if !result.is_null() {
+            self.used.set(self.used.get() + new_size - old_size);
+        }
+        result
+    }
+
+    unsafe fn free(&self, ptr: *mut c_void, size: usize) {
+        self.used.set(self.used.get() - size);
+        SystemAllocator.free(ptr, size)
+    }
+}
+
+/// Observer of the allocations passing through an [`AllocLayer`]
+pub(crate) trait AllocHook {
+    /// Whether to allow growing a block from `old` to `new` bytes
+    fn allow(&mut self, _old: usize, _new: usize) -> bool {
+        true
+    }
+
+    /// A block was changed from `old` to `new` bytes, `old` is 0 for allocation and `new` is 0 for free
+    fn changed(&mut self, old: usize, new: usize);
+}
+
+/// A layer in the allocator chain of a lua state, which forwards the allocations to the allocator under it.
+/// The layers can be installed and removed in any order, the chain is kept linked when a layer is removed
+pub(crate) struct AllocLayer {
+    alloc: lua_Alloc,
+    ud: *mut c_void,
+    hook: Box<dyn AllocHook>,
+}
+
+unsafe extern "C-unwind" fn layered_alloc(
+    ud: *mut c_void,
+    ptr: *mut c_void,
+    osize: usize,
+    nsize: usize,
+) -> *mut c_void {
+    let this = &mut *ud.cast::<AllocLayer>();
+    let old = if ptr.is_null() { 0 } else { osize };
+    if nsize > old && !this.hook.allow(old, nsize) {
+        return core::ptr::null_mut();
+    }
+    let result = (this.alloc)(this.ud, ptr, osize, nsize);
+    if nsize == 0 || !result.is_null() {
+        this.hook.changed(old, nsize);
+    }
+    result
+}
+
+fn is_layer(alloc: lua_Alloc) -> bool {
+    alloc as usize == layered_alloc as lua_Alloc as usize
+}
+
+impl AllocLayer {
+    /// Install a layer on the top of the allocator chain of `l`, which should be removed by [`Self::remove`] before dropped
+    pub(crate) fn install(l: *mut lua_State, hook: impl AllocHook + 'static) -> Box<Self> {
+        let mut ud = core::ptr::null_mut();
+        let alloc = unsafe { lua_getallocf(l, &mut ud) };
+        let mut this = Box::new(Self {
+            alloc,
+            ud,
+            hook: Box::new(hook),
+        });
+        unsafe { lua_setallocf(l, layered_alloc, &mut *this as *mut Self as _) };
+        this
+    }
+
+    /// Unlink this layer from the allocator chain of `l` wherever it is, the layers above it are kept
+    pub(crate) fn remove(&self, l: *mut lua_State) {
+        let this = self as *const Self as *mut c_void;
+        unsafe {
+            let mut ud = core::ptr::null_mut();
+            let mut alloc = lua_getallocf(l, &mut ud);
+            if ud == this {
+                lua_setallocf(l, self.alloc, self.ud);
+                return;
+            }
+            while is_layer(alloc) {
+                let upper = &mut *ud.cast::<AllocLayer>();
+                if is_layer(upper.alloc) && upper.ud == this {
+                    upper.alloc = self.alloc;
+                    upper.ud = self.ud;
+                    return;
+                }
+                (alloc, ud) = (upper.alloc, upper.ud);
+            }
+        }
+    }
+}
+
+/// The allocations beyond `limit` bytes since installed will fail
+pub(crate) struct MemoryLimiter {
+    pub used: usize,
+    pub limit: usize,
+}
+
+impl AllocHook for MemoryLimiter {
+    fn allow(&mut self, old: usize, new: usize) -> bool {
+        self.used + (new - old) <= self.limit
+    }
+
+    fn changed(&mut self, old: usize, new: usize) {
+        // the blocks allocated before the limiter installed can be freed
+        self.used = (self.used + new).saturating_sub(old);
+    }
+}
+
+unsafe extern "C-unwind" fn panic_handler(l: *mut lua_State) -> i32 {
+    #[cfg(feature = "log")]
+    {
+        let s = State::from_raw_state(l);
+        log::error!(
+            "PANIC: unprotected error in call to Lua API ({})",
+            s.to_string_lossy(-1).unwrap_or_default()
+        );
+    }
+    #[cfg(not(feature = "log"))]
+    let _ = l;
+    0
+}
+
+// the warn functions of `luaL_newstate`, which are not exported by lauxlib
+
+fn write_warning(_msg: &[u8]) {
+    #[cfg(feature = "std")]
+    {
+        use std::io::Write;
+        let mut stderr = std::io::stderr();
+        let _ = stderr.write_all(_msg);
+        let _ = stderr.flush();
+    }
+}
+
+unsafe fn check_warn_control(l: *mut lua_State, msg: *const c_char, tocont: c_int) -> bool {
+    let msg = CStr::from_ptr(msg).to_bytes();
+    if tocont != 0 || msg.first() != Some(&b'@') {
+        return false;
+    }
+    match &msg[1..] {
+        b"off" => lua_setwarnf(l, Some(warnf_off), l.cast()),
+        b"on" => lua_setwarnf(l, Some(warnf_on), l.cast()),
+        _ => {}
+    }
+    true
+}
+
+unsafe extern "C-unwind" fn warnf_off(ud: *mut c_void, msg: *const c_char, tocont: c_int) {
+    check_warn_control(ud.cast(), msg, tocont);
+}
+
+unsafe extern "C-unwind" fn warnf_cont(ud: *mut c_void, msg: *const c_char, tocont: c_int) {
+    let l: *mut lua_State = ud.cast();
+    write_warning(CStr::from_ptr(msg).to_bytes());
+    if tocont != 0 {
+        lua_setwarnf(l, Some(warnf_cont), ud);
+    } else {
+        write_warning(b"\n");
+        lua_setwarnf(l, Some(warnf_on), ud);
+    }
+}
+
+unsafe extern "C-unwind" fn warnf_on(ud: *mut c_void, msg: *const c_char, tocont: c_int) {
+    if check_warn_control(ud.cast(), msg, tocont) {
+        return;
+    }
+    write_warning(b"Lua warning: ");
+    warnf_cont(ud, msg, tocont);
+}
+
 impl Lua {
     pub fn new() -> Self {
-        let result = Self(LuaInner(unsafe { State::from_raw_state(State::new()) }).into());
+        Self::from_inner(LuaInner(
+            unsafe { State::from_raw_state(State::new()) },
+            None,
+            Default::default(),
+        ))
+    }
+
+    pub fn with_open_libs() -> Self {
+        let this = Self::new();
+        this.open_libs();
+        this
+    }
+
+    /// Create a lua state with only the specified standard libraries opened,
+    /// the builtin bindings are not registered unless [`crate::binding::init_global`] called explicitly
+    #[cfg(feature = "bitflags")]
+    pub fn new_with(libs: StdLibSet) -> Result<Self> {
+        let this = Self::new();
+        this.open_std_libs(libs)?;
+        Ok(this)
+    }
+
+    /// Create a lua state whose memory is managed by the specified allocator,
+    /// the allocator will be dropped after the lua state closed
+    pub fn with_allocator<A: LuaAllocator + 'static>(alloc: A) -> Result<Self> {
+        let alloc = Box::new(alloc);
+        let l = unsafe { lua_newstate(allocator_wrapper::<A>, &*alloc as *const A as _) };
+        if l.is_null() {
+            return Err(Error::Memory("create lua state".into()));
+        }
+        let state = unsafe { State::from_raw_state(l) };
+        state.at_panic(Some(panic_handler));
+        // warnings are off by default, as `luaL_newstate` does
+        unsafe { lua_setwarnf(l, Some(warnf_off), l.cast()) };
+        Ok(Self::from_inner(LuaInner(
+            state,
+            Some(alloc),
+            Default::default(),
+        )))
+    }
+
+    /// Create a lua state whose memory usage can't exceed `limit` bytes,
+    /// the allocations beyond the limit fail and raise [`Error::Memory`]
+    pub fn with_memory_limit(limit: usize) -> Result<Self> {
+        Self::with_allocator(LimitedAllocator {
+            used: Cell::new(0),
+            limit,
+        })
+    }
+
+    fn from_inner(inner: LuaInner) -> Self {
+        let result = Self(inner.into());
+        // the extra space is not initialized by lua
+        if !extra_space_hooked() {
+            let space = Box::into_raw(Box::new(ExtraSpace::new()));
+            unsafe { *lua_getextraspace(result.state).cast::<*mut ExtraSpace>() = space };
+        }
         result
             .registry()
             .set(
@@ -26,24 +342,80 @@ impl Lua {
             .expect("init luainner");
         result
     }
+}
 
-    pub fn with_open_libs() -> Self {
-        let this = Self::new();
-        this.open_libs();
-        this
-    }
+/// The second field keeps the custom allocator alive until the state closed
+pub(crate) struct LuaInner(
+    pub State,
+    Option<Box<dyn Any>>,
+    pub(crate) RefCell<LastError>,
+);
+
+/// See [`State::last_error`]
+#[derive(Default)]
+pub(crate) struct LastError {
+    error: Option<Error>,
+    keep: bool,
 }
 
-#[derive(Debug)]
-pub(crate) struct LuaInner(pub State);
+impl core::fmt::Debug for LuaInner {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_tuple("LuaInner").field(&self.0).finish()
+    }
+}
 
 impl Drop for LuaInner {
     fn drop(&mut self) {
+        let space = (!extra_space_hooked()).then(|| self.0.extra_space());
         self.0.close();
+        if let Some(space) = space {
+            drop(unsafe { Box::from_raw(space) });
+        }
     }
 }
 
+/// Whether the extra space is managed by [`crate::lock`]
+#[inline(always)]
+fn extra_space_hooked() -> bool {
+    #[cfg(feature = "thread")]
+    return crate::lock::is_hooked();
+    #[cfg(not(feature = "thread"))]
+    false
+}
+
 impl State {
+    /// The most recent error raised out of the protected calls on this state, for post-mortem logging,
+    /// e.g. the error of an abandoned async task. It's cleared after read unless [`Self::set_keep_last_error`]
+    pub fn last_error(&self) -> Option<Error> {
+        let inner = self.try_lua_inner()?;
+        let mut last = inner.2.borrow_mut();
+        if last.keep {
+            last.error.as_ref().map(Error::duplicate)
+        } else {
+            last.error.take()
+        }
+    }
+
+    /// Keep the last error after [`Self::last_error`] read it, until the next error occurs
+    pub fn set_keep_last_error(&self, keep: bool) {
+        if let Some(inner) = self.try_lua_inner() {
+            inner.2.borrow_mut().keep = keep;
+        }
+    }
+
+    pub(crate) fn record_error(&self, err: &Error) {
+        if let Some(inner) = self.try_lua_inner() {
+            inner.2.borrow_mut().error = Some(err.duplicate());
+        }
+    }
+
+    /// The memory limit (in bytes) of the state created by [`Lua::with_memory_limit`]
+    pub fn memory_limit(&self) -> Option<usize> {
+        let inner = self.try_lua_inner()?;
+        let alloc = inner.1.as_ref()?.downcast_ref::<LimitedAllocator>()?;
+        Some(alloc.limit)
+    }
+
     pub(crate) fn lua_inner(&self) -> ArcLuaInner {
         self.try_lua_inner().expect("main state pointer not set")
     }
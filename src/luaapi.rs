@@ -1255,6 +1255,28 @@ pub enum Comparison {
     Le = LUA_OPLE as isize,
 }
 
+/// Kinds of chunks accepted by `luaL_loadbufferx`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadMode {
+    /// Only the source text, "t"
+    Text,
+    /// Only the precompiled bytecode, "b"
+    Binary,
+    /// Both of them, "bt"
+    TextAndBinary,
+}
+
+impl LoadMode {
+    /// The mode string passed to `luaL_loadbufferx`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Text => "t",
+            Self::Binary => "b",
+            Self::TextAndBinary => "bt",
+        }
+    }
+}
+
 /// Represents all possible Lua data types.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Type {
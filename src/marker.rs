@@ -1,11 +1,11 @@
 //! Helpers to simplify the type conversion between rust and lua
 
-use alloc::string::ToString;
+use alloc::string::{String, ToString};
 use alloc::{boxed::Box, vec::Vec};
 use core::{cell::RefCell, fmt::Debug, str::FromStr as CoreFromStr};
 
 use crate::{
-    convert::{FromLua, Index, ToLua, ToLuaMulti},
+    convert::{FromLua, FromLuaMulti, Index, ToLua, ToLuaMulti},
     error::{Error, Result, ToLuaResult},
     ffi,
     luaapi::{Reference, UnsafeLuaApi},
@@ -33,6 +33,21 @@ impl<T: ToLuaMulti> ToLuaMulti for NilError<T> {
     }
 }
 
+/// Mark an error result to be raised with the `Display` text and `source()` chain of the error, rather than `Debug`
+#[cfg(feature = "std")]
+pub struct DisplayError<T>(pub T);
+
+#[cfg(feature = "std")]
+impl<T: ToLuaMulti, E: std::error::Error> ToLuaMulti for DisplayError<core::result::Result<T, E>> {
+    #[inline(always)]
+    fn push_multi(self, s: &crate::state::State) -> Result<usize> {
+        match self.0 {
+            Ok(res) => res.push_multi(s),
+            Err(err) => Err(Error::runtime_display(&err)),
+        }
+    }
+}
+
 /// Represents an argument passed from lua on the stack
 #[derive(Clone, Copy, Debug)]
 pub struct ArgRef(pub Index);
@@ -44,6 +59,28 @@ pub struct RegVal {
     pub(crate) inner: ArcLuaInner,
 }
 
+/// Value stored in a weak table of the registry, see [`State::weak_registry_value`]
+pub struct WeakRegVal {
+    pub(crate) key: Box<u8>,
+    pub(crate) inner: ArcLuaInner,
+}
+
+impl WeakRegVal {
+    /// Get the stored value, returns `None` if it has been collected
+    pub fn upgrade<'a>(&self, s: &'a State) -> Option<ValRef<'a>> {
+        let val = s.weak_registry().ok()?.getp(&*self.key).ok()?;
+        (!val.is_nil()).then_some(val)
+    }
+}
+
+impl Drop for WeakRegVal {
+    fn drop(&mut self) {
+        if let Ok(weak) = self.inner.0.weak_registry() {
+            let _ = weak.setp(&*self.key, ());
+        }
+    }
+}
+
 /// Represents a strict typed value, such as an integer value
 #[derive(Clone, Copy)]
 pub struct Strict<I>(pub I);
@@ -51,6 +88,11 @@ pub struct Strict<I>(pub I);
 /// Represents a strict typed boolean value
 pub type StrictBool = Strict<bool>;
 
+/// Represents an integer value converted with checking, a number which has fractional part
+/// or is out of the range of the target type results a convert error instead of being truncated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checked<I>(pub I);
+
 /// Represents an iterator will be converted to a lua array table
 pub struct IterVec<T: ToLua, I: Iterator<Item = T>>(pub I);
 
@@ -115,6 +157,64 @@ impl ToLua for ArgRef {
         Some(|this, s: &State| Ok(s.push_value(this.0)));
 }
 
+/// Distinguishes the absent value from an explicit nil when converting the values of a call,
+/// e.g. a function returning nothing is [`Presence::Absent`], and returning nil is `Presence::Present(None)`
+/// for `Presence<Option<T>>`, while a plain `Option<T>` is `None` in both cases
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Presence<T> {
+    Absent,
+    Present(T),
+}
+
+impl<'a, T: FromLua<'a>> FromLuaMulti<'a> for Presence<T> {
+    const COUNT: usize = 1;
+
+    fn from_lua_multi(s: &'a State, begin: Index) -> Result<Self> {
+        if begin > s.get_top() {
+            Ok(Self::Absent)
+        } else {
+            T::from_lua_multi(s, begin).map(Self::Present)
+        }
+    }
+}
+
+/// Converts the `value, error` convention of lua functions, which is the reverse of [`NilError`],
+/// it's `Err` with the second value as string if the first value is nil, a `false` value is still `Ok`
+impl<'a, T: FromLua<'a>> FromLuaMulti<'a> for core::result::Result<T, String> {
+    const COUNT: usize = 2;
+
+    fn from_lua_multi(s: &'a State, begin: Index) -> Result<Self> {
+        if !s.is_none_or_nil(begin) {
+            T::from_lua_multi(s, begin).map(Ok)
+        } else {
+            Ok(Err(if s.is_none_or_nil(begin + 1) {
+                "unknown error".into()
+            } else {
+                s.val(begin + 1).tostring().into_owned()
+            }))
+        }
+    }
+}
+
+/// Return value of the builder style methods, which returns the `self` userdata (the first argument) for chaining calls
+///
+/// ```rust,ignore
+/// mt.add_mut("arg", |this: &mut Self, arg: &str| {
+///     this.args.push(arg.into());
+///     Chainable
+/// })?;
+/// // in lua: cmd:arg('a'):arg('b')
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Chainable;
+
+impl ToLua for Chainable {
+    const __PUSH: Option<fn(Self, &State) -> Result<()>> = Some(|_, s: &State| {
+        s.push_value(1);
+        Ok(())
+    });
+}
+
 impl ToLua for &RegVal {
     const __PUSH: Option<fn(Self, &State) -> Result<()>> = Some(|this, s| unsafe {
         ffi::lua_rawgeti(s.raw_state(), ffi::LUA_REGISTRYINDEX, this.reference.0 as _);
@@ -130,6 +230,18 @@ impl Drop for RegVal {
     }
 }
 
+/// Restore the overridden global value when dropped, see [`State::override_global`]
+pub struct GlobalGuard {
+    pub(crate) name: String,
+    pub(crate) old: RegVal,
+}
+
+impl Drop for GlobalGuard {
+    fn drop(&mut self) {
+        let _ = self.old.inner.0.global().set(self.name.as_str(), &self.old);
+    }
+}
+
 /// Represents an iterator
 pub struct StaticIter<'a, T> {
     pub(crate) iter: Box<dyn Iterator<Item = T> + 'a>,
@@ -195,7 +307,7 @@ impl<T: ToLuaMulti + 'static> StaticIter<'static, T> {
 ///
 /// Notice: this type can only be used at the end of a function
 #[derive(Debug, Default)]
-pub struct Pushed(usize);
+pub struct Pushed(pub(crate) usize);
 
 impl ToLuaMulti for Pushed {
     #[inline(always)]
@@ -274,6 +386,10 @@ impl<T: ToLua> ToLuaMulti for MultiRet<T> {
     }
 }
 
+/// Alias to [`MultiRet`], spreads the elements of a `Vec` as multiple values like `table.unpack`,
+/// while the `Vec` itself is converted to a single array table
+pub use self::MultiRet as Spread;
+
 /// Alias to `MultiRet<Value<'a>>`
 pub type MultiValue<'a> = MultiRet<Value<'a>>;
 
@@ -358,12 +474,28 @@ where
     #[inline(always)]
     fn from_lua(lua: &'a State, val: ValRef<'a>) -> Result<Self> {
         T::from_bits(T::Bits::from_lua(lua, val)?)
-            .ok_or("invalid bitflags")
-            .lua_result()
+            .ok_or_else(|| {
+                Error::convert(alloc::format!(
+                    "unknown bits for {}",
+                    core::any::type_name::<T>()
+                ))
+            })
             .map(Self)
     }
 }
 
+#[cfg(feature = "bitflags")]
+impl State {
+    /// Convert an integer value to bitflags, unknown bits are treated as error
+    #[inline(always)]
+    pub fn from_flags<'a, T: bitflags::Flags>(&'a self, val: &ValRef<'a>) -> Result<T>
+    where
+        T::Bits: FromLua<'a>,
+    {
+        BitFlags::<T>::from_lua(self, val.clone()).map(|flags| flags.0)
+    }
+}
+
 #[cfg(feature = "bitflags")]
 impl<T: bitflags::Flags> ToLua for BitFlags<T>
 where
@@ -2,15 +2,20 @@
 
 pub use crate::convert::*;
 pub use crate::coroutine::{Coroutine, CoroutineWithRef};
-pub use crate::error::{Error as LuaError, Result as LuaResult, ToLuaResult};
+pub use crate::error::{Error as LuaError, Result as LuaResult, ToLuaResult, TracedError};
+pub use crate::gc::GcPhase;
+pub use crate::hook::{Budget, HookAction};
 pub use crate::lua::*;
-pub use crate::luaapi::{Reference, ThreadStatus, Type as LuaType};
+pub use crate::luaapi::{Comparison, LoadMode, Reference, ThreadStatus, Type as LuaType};
 pub use crate::marker::{FromStr as LuaFromStr, *};
-pub use crate::state::State as LuaState;
+pub use crate::scope::Scope;
+#[cfg(feature = "bitflags")]
+pub use crate::state::StdLibSet;
+pub use crate::state::{BalanceGuard, NumberFormat, StackFrame, State as LuaState, StdLib};
 pub use crate::userdata::{UserData, UserdataRegistry};
 pub use crate::value::{
-    Function as LuaFunction, LuaString, LuaThread, LuaUserData, Table as LuaTable, ValRef,
-    Value as LuaValue,
+    ArrayIndex, CallCounter, CoResult, Function as LuaFunction, LuaString, LuaThread, LuaUserData,
+    OwnedKey, OwnedValue, Table as LuaTable, ValRef, Value as LuaValue,
 };
 
 #[cfg(all(feature = "std", feature = "serde"))]
@@ -0,0 +1,102 @@
+use alloc::vec::Vec;
+use core::{cell::RefCell, marker::PhantomData};
+
+use crate::{
+    error::{Error, Result},
+    state::State,
+    userdata::{MaybePointer, MaybePtrRef, UserData, UserdataRegistry},
+    value::LuaUserData,
+};
+
+/// Scope for creating lua values which borrow rust data, see [`State::scope`]
+///
+/// The userdata created by the scope are invalidated when the scope ends,
+/// accessing them from lua after that raises an error
+pub struct Scope<'l, 's> {
+    state: &'l State,
+    items: RefCell<Vec<LuaUserData<'l>>>,
+    _scope: PhantomData<&'s mut &'s ()>,
+}
+
+impl<'l, 's> Scope<'l, 's> {
+    /// Create a userdata referencing `data` without moving it
+    pub fn create_userdata_ref<T: UserData<Trans = MaybePointer<T>>>(
+        &self,
+        data: &'s T,
+    ) -> Result<LuaUserData<'l>> {
+        let udata: LuaUserData = self.state.new_val(MaybePtrRef(data))?.try_into()?;
+        self.items.borrow_mut().push(udata.clone());
+        Ok(udata)
+    }
+
+    /// Create a userdata as a view of `data`, the bytes can be accessed by index (starts from 1) in lua,
+    /// and the modifications are applied to `data` directly
+    pub fn create_buffer(&self, data: &'s mut [u8]) -> Result<LuaUserData<'l>> {
+        let udata = self.state.new_userdata(ScopedBuffer {
+            ptr: data.as_mut_ptr(),
+            len: data.len(),
+        })?;
+        self.items.borrow_mut().push(udata.clone());
+        Ok(udata)
+    }
+}
+
+/// Byte buffer borrowed from rust, see [`Scope::create_buffer`]
+struct ScopedBuffer {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl ScopedBuffer {
+    fn index(&self, i: usize) -> Result<usize> {
+        i.checked_sub(1)
+            .filter(|&i| i < self.len)
+            .ok_or_else(|| Error::runtime(alloc::format!("buffer index {i} out of range")))
+    }
+}
+
+impl UserData for ScopedBuffer {
+    const TYPE_NAME: &'static str = "ScopedBuffer";
+
+    fn methods(methods: UserdataRegistry<Self>) -> Result<()> {
+        // write bytes at the position `offset`, returns the count of bytes written
+        methods.add("write", |this: &Self, offset: usize, data: &[u8]| {
+            let start = this.index(offset)?;
+            let count = data.len().min(this.len - start);
+            unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), this.ptr.add(start), count) };
+            Result::Ok(count)
+        })?;
+        Ok(())
+    }
+
+    fn metatable(mt: UserdataRegistry<Self>) -> Result<()> {
+        mt.add("__len", |this: &Self| this.len)?;
+        mt.add("__index", |this: &Self, i: usize| {
+            this.index(i).map(|i| unsafe { *this.ptr.add(i) })
+        })?;
+        mt.add("__newindex", |this: &Self, i: usize, byte: u8| {
+            this.index(i).map(|i| unsafe { *this.ptr.add(i) = byte })
+        })?;
+        Ok(())
+    }
+}
+
+impl Drop for Scope<'_, '_> {
+    fn drop(&mut self) {
+        for udata in self.items.get_mut().drain(..) {
+            let _ = udata.call_close_and_remove_metatable();
+        }
+    }
+}
+
+impl State {
+    /// Run `f` with a [`Scope`], in which the lua values can borrow non-'static rust data
+    pub fn scope<'l, 's, R>(&'l self, f: impl FnOnce(&Scope<'l, 's>) -> Result<R>) -> Result<R> {
+        let scope = Scope {
+            state: self,
+            items: Default::default(),
+            _scope: PhantomData,
+        };
+        f(&scope)
+    }
+}
@@ -24,6 +24,11 @@ use ::serde::{
     },
 };
 
+std::thread_local! {
+    /// Whether the serialized none/unit are converted to [`State::null_value`], see [`State::load_from_deserializer_with_null`]
+    static NULL_AS_SENTINEL: core::cell::Cell<bool> = const { core::cell::Cell::new(false) };
+}
+
 #[derive(Clone, Debug, PartialEq, Display)]
 pub enum DesErr {
     // One or more variants that can be created by data structures through the
@@ -87,6 +92,24 @@ impl State {
         serde_transcode::transcode(deserializer, LuaSerializer(self))
     }
 
+    /// Like [`Self::load_from_deserializer`], but the null values are converted to [`Self::null_value`] instead of nil,
+    /// so the map entries of null are kept
+    pub fn load_from_deserializer_with_null<'l: 'de, 'de, D: Deserializer<'de>>(
+        &'l self,
+        deserializer: D,
+    ) -> Result<ValRef<'l>, LuaError> {
+        struct Restore(bool);
+
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                NULL_AS_SENTINEL.with(|null| null.set(self.0));
+            }
+        }
+
+        let _restore = Restore(NULL_AS_SENTINEL.with(|null| null.replace(true)));
+        self.load_from_deserializer(deserializer)
+    }
+
     /// A metatable attachable to a Lua table to systematically encode it as Array (instead of Map).
     /// As result, encoded Array will contain only sequence part of the table, with the same length as the # operator on that table.
     pub fn array_metatable(&self) -> LuaResult<LuaTable> {
@@ -98,6 +121,21 @@ impl State {
         self.top_val().try_into()
     }
 
+    /// Check if `mt` is the [`Self::array_metatable`], its pointer is cached in the extra space of lua state,
+    /// so the checks after the first one don't touch the lua stack
+    pub fn is_array_metatable(&self, mt: &LuaTable) -> bool {
+        let space = self.extra_space();
+        let mut p = unsafe { (*space).array_mt };
+        if p.is_null() {
+            match self.array_metatable() {
+                Ok(array_mt) => p = array_mt.to_pointer(),
+                Err(_) => return false,
+            }
+            unsafe { (*space).array_mt = p };
+        }
+        mt.to_pointer() == p
+    }
+
     /// A special value to encode/decode optional (none) values.
     pub fn null_value(&self) -> LuaValue {
         LuaValue::light_userdata(Self::null_value as *const ())
@@ -429,7 +467,7 @@ impl<'a> Serializer for LuaSerializer<'a> {
         self.0.new_val(v)
     }
     fn serialize_none(self) -> Result<Self::Ok, Self::Error> {
-        self.0.new_val(())
+        self.serialize_unit()
     }
     fn serialize_some<T: ?Sized>(self, value: &T) -> Result<Self::Ok, Self::Error>
     where
@@ -439,7 +477,11 @@ impl<'a> Serializer for LuaSerializer<'a> {
     }
 
     fn serialize_unit(self) -> Result<Self::Ok, Self::Error> {
-        self.0.new_val(())
+        if NULL_AS_SENTINEL.with(|null| null.get()) {
+            self.0.new_val(self.0.null_value())
+        } else {
+            self.0.new_val(())
+        }
     }
     fn serialize_unit_struct(self, name: &'static str) -> Result<Self::Ok, Self::Error> {
         let mut s = LuaTableSerializer::begin(self.0, 1)?;
@@ -553,14 +595,20 @@ impl<'a> Serializer for LuaSerializer<'a> {
         Ok(LuaTableSerializer::begin(self.0, len)?)
     }
 
-    // TODO:
+    /// Serialized as integer if it fits in [`lua_Integer`], otherwise as decimal string
     fn serialize_i128(self, v: i128) -> Result<Self::Ok, Self::Error> {
-        self.serialize_i64(v as _)
+        match i64::try_from(v) {
+            Ok(i) => self.serialize_i64(i),
+            Err(_) => self.serialize_str(&v.to_string()),
+        }
     }
 
-    // TODO:
+    /// Serialized as integer if it fits in [`lua_Integer`], otherwise as decimal string
     fn serialize_u128(self, v: u128) -> Result<Self::Ok, Self::Error> {
-        self.serialize_u64(v as _)
+        match i64::try_from(v) {
+            Ok(i) => self.serialize_i64(i),
+            Err(_) => self.serialize_str(&v.to_string()),
+        }
     }
 
     // fn collect_str<T: ?Sized>(self, value: &T) -> Result<Self::Ok, Self::Error>
@@ -595,6 +643,8 @@ impl<'de> Deserializer<'de> for &'de ValRef<'_> {
                     visitor.visit_f64(self.to_number())
                 }
             }
+            // the string which is not valid utf8 is regarded as binary data
+            Type::String if self.to_str().is_none() => self.deserialize_bytes(visitor),
             Type::String => self.deserialize_str(visitor),
             Type::Boolean if self.to_bool() => self.deserialize_bool(visitor),
             Type::Boolean => self.deserialize_unit(visitor),
@@ -603,12 +653,7 @@ impl<'de> Deserializer<'de> for &'de ValRef<'_> {
                     let is_array = t
                         .metatable()
                         .map_err(DeErr::custom)?
-                        .filter(|mt| {
-                            self.state
-                                .array_metatable()
-                                .map(|a| a.raw_equal(mt))
-                                .unwrap_or_default()
-                        })
+                        .filter(|mt| self.state.is_array_metatable(mt))
                         .is_some();
 
                     if is_array || t.raw_len() > 0 {
@@ -665,6 +710,22 @@ impl<'de> Deserializer<'de> for &'de ValRef<'_> {
         visitor.visit_i64(self.cast().map_err(|_| DesErr::ExpectedInteger)?)
     }
 
+    /// Accepts the integer or the decimal string serialized by `serialize_i128`
+    fn deserialize_i128<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_i128(self.cast().map_err(|_| DesErr::ExpectedInteger)?)
+    }
+
+    /// Accepts the integer or the decimal string serialized by `serialize_u128`
+    fn deserialize_u128<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_u128(self.cast().map_err(|_| DesErr::ExpectedInteger)?)
+    }
+
     /// Hint that the `Deserialize` type is expecting a `u8` value.
     fn deserialize_u8<V>(self, visitor: V) -> Result<V::Value, Self::Error>
     where
@@ -1173,12 +1234,7 @@ impl Serialize for ValRef<'_> {
                         let is_array = t
                             .metatable()
                             .map_err(Error::custom)?
-                            .filter(|mt| {
-                                self.state
-                                    .array_metatable()
-                                    .map(|a| a.raw_equal(mt))
-                                    .unwrap_or_default()
-                            })
+                            .filter(|mt| self.state.is_array_metatable(mt))
                             .is_some();
 
                         t.state.check_stack(3).map_err(Error::custom)?;
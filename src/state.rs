@@ -2,14 +2,17 @@ use crate::{
     convert::*,
     error::{Error, Result},
     ffi::*,
-    luaapi::{ThreadStatus, Type},
-    marker::RegVal,
+    luaapi::{LoadMode, ThreadStatus, Type},
+    marker::{GlobalGuard, RegVal, WeakRegVal},
     str::*,
-    value::{ValRef, Value},
+    value::{Function, LuaString, Table, ValRef, Value},
 };
 
-use alloc::{collections::BinaryHeap as Slots, format};
-use core::{cell::Cell, cell::RefCell, ffi::c_int, str};
+use alloc::{
+    boxed::Box, collections::BTreeSet, collections::BinaryHeap as Slots, format, string::String,
+    vec::Vec,
+};
+use core::{cell::Cell, cell::RefCell, ffi::c_int, ffi::c_void, str};
 
 /// Safe wrapper for operation to lua_State
 #[derive(Debug)]
@@ -20,6 +23,24 @@ pub struct State {
     pub(crate) free: RefCell<Slots<i32>>,
 }
 
+/// The data stored in the extra space of lua state, shared by the coroutines
+#[repr(C)]
+pub(crate) struct ExtraSpace {
+    /// The pointer stored by [`State::set_extra_ptr`]
+    pub(crate) user: *mut c_void,
+    /// Cached pointer of [`State::array_metatable`], null if not queried yet
+    pub(crate) array_mt: *const c_void,
+}
+
+impl ExtraSpace {
+    pub(crate) const fn new() -> Self {
+        Self {
+            user: core::ptr::null_mut(),
+            array_mt: core::ptr::null(),
+        }
+    }
+}
+
 #[cfg(feature = "unsafe_send_sync")]
 unsafe impl Send for State {}
 #[cfg(feature = "unsafe_send_sync")]
@@ -32,6 +53,251 @@ impl State {
         self.load(script, name)?.pcall_void(())
     }
 
+    /// Like [`Self::exec`], but the compiled chunk is cached in the registry by its source,
+    /// and reused when the same source is evaluated again.
+    ///
+    /// It's meant for a fixed set of sources, the cache is dropped when it holds 256 chunks,
+    /// and it can be dropped explicitly by [`Self::clear_eval_cache`]
+    pub fn eval_cached<'a, R: FromLuaMulti<'a>>(&'a self, src: &str) -> Result<R> {
+        let registry = self.registry();
+        let cache = Table::try_from(registry.getp(&EVAL_CACHE)?).ok();
+        if let Some(cache) = &cache {
+            if let Ok(func) = Function::try_from(cache.raw_get(src)?) {
+                return func.pcall(());
+            }
+        }
+        // the count of cached chunks is stored at index 0
+        let cache = match cache {
+            Some(cache) if cache.raw_geti(0)?.to_integer() < EVAL_CACHE_SIZE as lua_Integer => {
+                cache
+            }
+            _ => {
+                let cache = self.new_table()?;
+                registry.setp(&EVAL_CACHE, cache.clone())?;
+                cache
+            }
+        };
+        let func = self.load(src, None)?;
+        cache.raw_set(src, func.clone())?;
+        cache.raw_seti(0, cache.raw_geti(0)?.to_integer() + 1)?;
+        func.pcall(())
+    }
+
+    /// Get the lua string of `s` cached in the registry, the same string is returned for the same content,
+    /// useful for the keys used repeatedly. The cached strings are kept until [`Self::clear_interned`]
+    pub fn intern(&self, s: &str) -> Result<LuaString> {
+        let registry = self.registry();
+        let interned = match Table::try_from(registry.getp(&INTERNED)?) {
+            Ok(interned) => interned,
+            Err(_) => {
+                let interned = self.new_table()?;
+                registry.setp(&INTERNED, interned.clone())?;
+                interned
+            }
+        };
+        if let Ok(string) = LuaString::try_from(interned.raw_get(s)?) {
+            return Ok(string);
+        }
+        let string = self.new_string(s)?;
+        interned.raw_set(&*string, &*string)?;
+        Ok(string)
+    }
+
+    /// Drop the strings cached by [`Self::intern`]
+    pub fn clear_interned(&self) -> Result<()> {
+        self.registry().setp(&INTERNED, ())
+    }
+
+    /// Drop the chunks cached by [`Self::eval_cached`]
+    pub fn clear_eval_cache(&self) -> Result<()> {
+        self.registry().setp(&EVAL_CACHE, ())
+    }
+
+    /// Load lua script as statements and execute it, returns the values of its `return` statement
+    #[inline]
+    pub fn exec<'a, R: FromLuaMulti<'a>>(&'a self, script: &str, name: Option<&str>) -> Result<R> {
+        self.load(script, name)?.pcall(())
+    }
+
+    /// Format the arguments by `string.format` of lua, the string library must be opened
+    pub fn format<A: ToLuaMulti>(&self, fmt: &str, args: A) -> Result<String> {
+        let format = self.global().get("string")?.get("format")?;
+        if format.type_of() != Type::Function {
+            return Err(Error::runtime("string.format is not available"));
+        }
+        format.pcall(FormatArgs(fmt, args))
+    }
+
+    /// Set how [`ValRef::tostring`] converts the float numbers, [`NumberFormat::Lua`] by default
+    pub fn set_number_format(&self, fmt: NumberFormat) -> Result<()> {
+        if fmt != NumberFormat::Lua {
+            NUMBER_FORMAT_SET.store(true, core::sync::atomic::Ordering::Relaxed);
+        }
+        self.registry().setp(
+            &NUMBER_FORMAT,
+            match fmt {
+                NumberFormat::Lua => None,
+                NumberFormat::Shortest => Some(-1),
+                NumberFormat::Precision(p) => Some(p as i32),
+            },
+        )
+    }
+
+    /// The format set by [`Self::set_number_format`]
+    pub fn number_format(&self) -> NumberFormat {
+        use crate::luaapi::UnsafeLuaApi;
+
+        // skip the registry lookup if no custom format was ever set
+        if !NUMBER_FORMAT_SET.load(core::sync::atomic::Ordering::Relaxed)
+            || self.check_stack(1).is_err()
+        {
+            return NumberFormat::default();
+        }
+        self.raw_getp(LUA_REGISTRYINDEX, &NUMBER_FORMAT);
+        let result = match self.to_integerx(-1) {
+            Some(p) if p < 0 => NumberFormat::Shortest,
+            Some(p) => NumberFormat::Precision(p as u8),
+            None => NumberFormat::Lua,
+        };
+        self.pop(1);
+        result
+    }
+
+    /// List the globals with their type names except the standard library entries, sorted by name,
+    /// useful to check what a script defined
+    pub fn globals_summary(&self) -> Result<Vec<(String, &'static str)>> {
+        let mut result = self
+            .global()
+            .pairs()?
+            .filter_map(|(k, v)| match k {
+                Value::String(k) => {
+                    let k = k.to_string_lossy().into_owned();
+                    (!STD_GLOBALS.contains(&k.as_str())).then(|| (k, v.type_name()))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        result.sort();
+        Ok(result)
+    }
+
+    /// Register a C-like enum as a read-only global table, the variants are integer constants like `Color.Red`,
+    /// and the names can be looked up by the values reversely like `Color[0] == 'Red'`
+    pub fn register_enum(&self, name: &str, variants: &[(&str, i64)]) -> Result<()> {
+        let consts = self.new_table_with_size(0, variants.len() as i32 * 2)?;
+        for &(variant, value) in variants {
+            consts.raw_set(variant, value)?;
+            consts.raw_set(value, variant)?;
+        }
+
+        let mt = self.new_table_with_size(0, 3)?;
+        mt.raw_set("__index", consts)?;
+        let enum_name = String::from(name);
+        mt.raw_set(
+            "__newindex",
+            self.new_function(move |_, ()| {
+                Err::<(), _>(Error::runtime(format!("enum {enum_name} is read-only")))
+            })?,
+        )?;
+        mt.raw_set("__metatable", false)?;
+
+        let proxy = self.new_table()?;
+        proxy.set_metatable(mt)?;
+        self.global().set(name, proxy)
+    }
+
+    /// Only allow the modules in `allowed` to be required, by a searcher running before the others,
+    /// which raises error for the other modules. Calling again replaces the previous whitelist.
+    ///
+    /// The modules already loaded in `package.loaded` are not affected, since `require` doesn't search them
+    pub fn set_require_whitelist(&self, allowed: &[&str]) -> Result<()> {
+        static WHITELIST_SEARCHER: u8 = 0;
+
+        let searchers: Table = self
+            .global()
+            .get("package")?
+            .get("searchers")?
+            .try_into()
+            .map_err(|_| Error::runtime("package library is not opened"))?;
+
+        let allowed = allowed
+            .iter()
+            .map(|&name| String::from(name))
+            .collect::<BTreeSet<_>>();
+        let searcher = self.new_function(move |_, name: &str| {
+            // returns nothing to let the next searcher continue
+            if allowed.contains(name) {
+                Ok(())
+            } else {
+                Err(Error::runtime(format!("module '{name}' is not allowed")))
+            }
+        })?;
+
+        let registry = self.registry();
+        let old = registry.getp(&WHITELIST_SEARCHER)?;
+        registry.setp(&WHITELIST_SEARCHER, searcher.clone())?;
+        if !old.is_nil() {
+            for i in 1..=searchers.raw_len_i64() {
+                if searchers.raw_geti(i)?.raw_equal(&old) {
+                    return searchers.raw_seti(i, searcher);
+                }
+            }
+        }
+        searchers.raw_insert(1, searcher)
+    }
+
+    /// Append a searcher to `package.searchers` for `require`, the function returned by `searcher`
+    /// is used as the module loader, and `None` lets the searching continue
+    pub fn add_searcher(
+        &self,
+        searcher: impl for<'a> Fn(&'a State, &str) -> Result<Option<Function<'a>>> + 'static,
+    ) -> Result<()> {
+        let searchers: Table = self
+            .global()
+            .get("package")?
+            .get("searchers")?
+            .try_into()
+            .map_err(|_| Error::runtime("package library is not opened"))?;
+        searchers.push(self.new_function(move |s, name: &str| searcher(s, name))?)
+    }
+
+    /// Limit the length of the strings produced by `string.rep`, which is an easy way to exhaust the memory,
+    /// the string library must be opened
+    pub fn set_max_string_length(&self, max: usize) -> Result<()> {
+        static STRING_REP: u8 = 0;
+
+        let string: Table = self
+            .global()
+            .get("string")?
+            .try_into()
+            .map_err(|_| Error::runtime("string library is not opened"))?;
+        // keep the original `string.rep`, in case of being set again
+        let registry = self.registry();
+        if registry.getp(&STRING_REP)?.is_nil() {
+            registry.setp(&STRING_REP, string.raw_get("rep")?)?;
+        }
+
+        let rep = self.new_function(
+            move |s, (text, n, sep): (&[u8], lua_Integer, Option<&[u8]>)| {
+                let n = n.max(0) as usize;
+                let sep_len = sep.map_or(0, <[u8]>::len);
+                let len = text
+                    .len()
+                    .saturating_mul(n)
+                    .saturating_add(sep_len.saturating_mul(n.saturating_sub(1)));
+                if len > max {
+                    return Err(Error::runtime(format!(
+                        "resulting string too large: {len} > {max}"
+                    )));
+                }
+                s.registry()
+                    .getp(&STRING_REP)?
+                    .pcall::<_, ValRef>((text, n, sep))
+            },
+        )?;
+        string.raw_set("rep", rep)
+    }
+
     #[inline(always)]
     pub fn registry_value<V: ToLua>(&self, val: V) -> Result<RegVal> {
         self.registry().reference(val).map(|r| RegVal {
@@ -40,6 +306,91 @@ impl State {
         })
     }
 
+    /// Store the value in a weak table of the registry, which doesn't prevent it from being collected
+    pub fn weak_registry_value<V: ToLua>(&self, val: V) -> Result<WeakRegVal> {
+        let key = Box::new(0u8);
+        self.weak_registry()?.setp(&*key, val)?;
+        Ok(WeakRegVal {
+            key,
+            inner: self.lua_inner(),
+        })
+    }
+
+    pub(crate) fn weak_registry(&self) -> Result<Table> {
+        use crate::luaapi::UnsafeLuaApi;
+
+        static WEAK_REGISTRY: u8 = 0;
+
+        self.check_stack(3)?;
+        if self.raw_getp(LUA_REGISTRYINDEX, &WEAK_REGISTRY) != Type::Table {
+            self.pop(1);
+            self.create_table(0, 0);
+            unsafe { crate::userdata::get_weak_meta(self)? };
+            self.set_metatable(-2);
+            self.push_value(-1);
+            self.raw_setp(LUA_REGISTRYINDEX, &WEAK_REGISTRY);
+        }
+        self.top_val().try_into()
+    }
+
+    /// Table of the thread names set by [`LuaThread::set_name`](crate::value::LuaThread::set_name), the threads are weak keys
+    pub(crate) fn thread_names(&self) -> Result<Table> {
+        use crate::luaapi::UnsafeLuaApi;
+
+        self.check_stack(4)?;
+        if self.raw_getp(LUA_REGISTRYINDEX, &THREAD_NAMES) != Type::Table {
+            self.pop(1);
+            self.create_table(0, 0);
+            self.create_table(0, 1);
+            self.push_string("k");
+            self.set_field(-2, crate::cstr!("__mode"));
+            self.set_metatable(-2);
+            self.push_value(-1);
+            self.raw_setp(LUA_REGISTRYINDEX, &THREAD_NAMES);
+        }
+        self.top_val().try_into()
+    }
+
+    /// Name of current thread, see [`LuaThread::set_name`](crate::value::LuaThread::set_name)
+    pub fn thread_name(&self) -> Option<String> {
+        use crate::luaapi::UnsafeLuaApi;
+
+        self.check_stack(2).ok()?;
+        let _guard = self.stack_guard();
+        if self.raw_getp(LUA_REGISTRYINDEX, &THREAD_NAMES) != Type::Table {
+            return None;
+        }
+        self.push_thread();
+        self.raw_get(-2);
+        self.to_str(-1).map(Into::into)
+    }
+
+    /// Store a pointer for the host in the extra space of lua state, which can be read by [`Self::extra_ptr`]
+    /// from any C function cheaply without registry lookup.
+    ///
+    /// Only one pointer can be stored, it's shared by all the coroutines of the lua state.
+    /// The lua state must be created by [`crate::lua::Lua`], or by a lua library built with the `lua_lock` hooks
+    #[inline]
+    pub fn set_extra_ptr<T>(&self, p: *mut T) {
+        unsafe { (*self.extra_space()).user = p.cast() }
+    }
+
+    /// The pointer stored by [`Self::set_extra_ptr`], null if not set
+    #[inline]
+    pub fn extra_ptr<T>(&self) -> *mut T {
+        unsafe { (*self.extra_space()).user.cast() }
+    }
+
+    /// The extra space is occupied by [`crate::lock`] if lua is built with its hooks, the data is stored in it,
+    /// otherwise it points to the data allocated by [`crate::lua::Lua`]
+    pub(crate) fn extra_space(&self) -> *mut ExtraSpace {
+        #[cfg(feature = "thread")]
+        if crate::lock::is_hooked() {
+            return &mut crate::lock::get_extra(self.state).space;
+        }
+        unsafe { *lua_getextraspace(self.state).cast::<*mut ExtraSpace>() }
+    }
+
     #[inline(always)]
     pub fn safe_index(&self, i: Index) -> bool {
         i <= self.base
@@ -50,6 +401,17 @@ impl State {
         StackGuard::from(self)
     }
 
+    /// Create a guard for the raw api code, which expects the stack top changed by `delta` when dropped,
+    /// the top is restored to the expected position, and a mismatch is asserted in debug builds
+    #[inline(always)]
+    pub fn balance_guard(&self, delta: i32) -> BalanceGuard {
+        BalanceGuard {
+            state: self,
+            top: self.stack_top(),
+            delta,
+        }
+    }
+
     #[track_caller]
     pub(crate) fn drop_valref<'a>(&'a self, val: &ValRef<'a>) {
         if val.index > self.base {
@@ -85,6 +447,215 @@ impl State {
     }
 }
 
+struct FormatArgs<'a, A>(&'a str, A);
+
+impl<A: ToLuaMulti> ToLuaMulti for FormatArgs<'_, A> {
+    fn value_count(&self) -> Option<usize> {
+        self.1.value_count().map(|n| n + 1)
+    }
+
+    fn push_multi(self, s: &State) -> Result<usize> {
+        self.0.push_multi(s)?;
+        self.1.push_multi(s).map(|n| n + 1)
+    }
+}
+
+/// Info of a function frame on the call stack, see [`State::frames`]
+#[derive(Debug, Clone)]
+pub struct StackFrame {
+    /// Printable source name, like `[string "..."]` or `[C]`
+    pub source: String,
+    /// Current line, -1 if not available
+    pub line: i32,
+    /// The line where the function definition starts
+    pub line_defined: i32,
+    pub name: Option<String>,
+    /// How the function was called, "global", "local", "method", "field" or ""
+    pub namewhat: String,
+    /// "Lua", "C" or "main"
+    pub what: String,
+    /// Name of the thread running this function, see [`LuaThread::set_name`](crate::value::LuaThread::set_name)
+    pub thread: Option<String>,
+}
+
+impl core::fmt::Display for StackFrame {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}:", self.source)?;
+        if self.line > 0 {
+            write!(f, "{}:", self.line)?;
+        }
+        match self.name.as_deref() {
+            Some(name) if !self.namewhat.is_empty() => {
+                write!(f, " in {} '{}'", self.namewhat, name)
+            }
+            _ if self.what == "main" => write!(f, " in main chunk"),
+            _ if self.what == "C" => write!(f, " in ?"),
+            _ => write!(f, " in function <{}:{}>", self.source, self.line_defined),
+        }
+    }
+}
+
+/// How the float numbers are converted to string, see [`State::set_number_format`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NumberFormat {
+    /// `%.14g` like `LUAI_NUMFFORMAT`, the same as `tostring` of lua, e.g. `0.1 + 0.2` is formatted as `0.3`
+    #[default]
+    Lua,
+    /// The shortest representation which converts back to the same number, e.g. `0.1 + 0.2` is formatted as `0.30000000000000004`
+    Shortest,
+    /// Like `%.{n}g` of C, e.g. `Precision(17)` formats `0.1` as `0.10000000000000001`
+    Precision(u8),
+}
+
+impl NumberFormat {
+    /// Format `n` in this format, a `.0` suffix is added if it looks like an integer, as lua does
+    pub fn format(self, n: lua_Number) -> String {
+        let mut result = match self {
+            _ if !n.is_finite() => {
+                return match n {
+                    n if n.is_nan() => if n.is_sign_negative() { "-nan" } else { "nan" }.into(),
+                    n if n > 0.0 => "inf".into(),
+                    _ => "-inf".into(),
+                }
+            }
+            // like `%.17g`, but with the shortest digits which convert back to the same number
+            Self::Shortest => {
+                let sci = format!("{n:e}");
+                let (mantissa, exp) = split_exponent(&sci);
+                if !(-4..17).contains(&exp) {
+                    with_exponent(mantissa, exp)
+                } else {
+                    format!("{n}")
+                }
+            }
+            Self::Lua => return Self::Precision(14).format(n),
+            Self::Precision(p) => {
+                let p = p.max(1) as usize;
+                let sci = format!("{:.*e}", p - 1, n);
+                let (mantissa, exp) = split_exponent(&sci);
+                if exp < -4 || exp >= p as i32 {
+                    with_exponent(trim_fraction(mantissa), exp)
+                } else {
+                    let fixed = format!("{:.*}", (p as i32 - 1 - exp) as usize, n);
+                    trim_fraction(&fixed).into()
+                }
+            }
+        };
+        if result.bytes().all(|c| c.is_ascii_digit() || c == b'-') {
+            result.push_str(".0");
+        }
+        result
+    }
+}
+
+fn split_exponent(sci: &str) -> (&str, i32) {
+    let (mantissa, exp) = sci.split_once('e').unwrap_or((sci, "0"));
+    (mantissa, exp.parse().unwrap_or_default())
+}
+
+fn with_exponent(mantissa: &str, exp: i32) -> String {
+    format!(
+        "{mantissa}e{}{:02}",
+        if exp < 0 { '-' } else { '+' },
+        exp.abs()
+    )
+}
+
+fn trim_fraction(s: &str) -> &str {
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        s
+    }
+}
+
+/// Lua standard libraries which can be opened separately, see [`State::open_std_lib`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StdLib {
+    Base,
+    Package,
+    Coroutine,
+    Table,
+    Io,
+    Os,
+    String,
+    Math,
+    Utf8,
+    Debug,
+}
+
+impl StdLib {
+    /// All the standard libraries, in the order opened by `luaL_openlibs`
+    pub const ALL: [StdLib; 10] = [
+        Self::Base,
+        Self::Package,
+        Self::Coroutine,
+        Self::Table,
+        Self::Io,
+        Self::Os,
+        Self::String,
+        Self::Math,
+        Self::Utf8,
+        Self::Debug,
+    ];
+
+    /// Module name of the library, `_G` for the base library
+    pub fn name(self) -> &'static CStr {
+        match self {
+            Self::Base => crate::cstr!("_G"),
+            Self::Package => crate::cstr!("package"),
+            Self::Coroutine => crate::cstr!("coroutine"),
+            Self::Table => crate::cstr!("table"),
+            Self::Io => crate::cstr!("io"),
+            Self::Os => crate::cstr!("os"),
+            Self::String => crate::cstr!("string"),
+            Self::Math => crate::cstr!("math"),
+            Self::Utf8 => crate::cstr!("utf8"),
+            Self::Debug => crate::cstr!("debug"),
+        }
+    }
+
+    fn opener(self) -> CFunction {
+        match self {
+            Self::Base => luaopen_base,
+            Self::Package => luaopen_package,
+            Self::Coroutine => luaopen_coroutine,
+            Self::Table => luaopen_table,
+            Self::Io => luaopen_io,
+            Self::Os => luaopen_os,
+            Self::String => luaopen_string,
+            Self::Math => luaopen_math,
+            Self::Utf8 => luaopen_utf8,
+            Self::Debug => luaopen_debug,
+        }
+    }
+}
+
+#[cfg(feature = "bitflags")]
+bitflags::bitflags! {
+    /// Set of [`StdLib`], see [`State::open_std_libs`]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct StdLibSet: u16 {
+        const BASE = 1 << 0;
+        const PACKAGE = 1 << 1;
+        const COROUTINE = 1 << 2;
+        const TABLE = 1 << 3;
+        const IO = 1 << 4;
+        const OS = 1 << 5;
+        const STRING = 1 << 6;
+        const MATH = 1 << 7;
+        const UTF8 = 1 << 8;
+        const DEBUG = 1 << 9;
+    }
+}
+
+#[cfg(feature = "bitflags")]
+impl From<StdLib> for StdLibSet {
+    fn from(lib: StdLib) -> Self {
+        Self::from_bits_retain(1 << lib as u16)
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct StackGuard<'a> {
     state: &'a State,
@@ -105,6 +676,94 @@ impl<'a> From<&'a State> for StackGuard<'a> {
     }
 }
 
+/// Guard checking the stack balance, see [`State::balance_guard`]
+#[derive(Debug)]
+pub struct BalanceGuard<'a> {
+    state: &'a State,
+    top: Index,
+    delta: i32,
+}
+
+impl<'a> BalanceGuard<'a> {
+    /// The stack top when the guard created
+    #[inline(always)]
+    pub fn top(&self) -> i32 {
+        self.top
+    }
+}
+
+static ARG_ERROR_LOCATION: u8 = 0;
+static NO_TRACEBACK: u8 = 0;
+static TRUSTED_BYTECODE: u8 = 0;
+static THREAD_NAMES: u8 = 0;
+static EVAL_CACHE: u8 = 0;
+/// The max number of chunks cached by [`State::eval_cached`]
+const EVAL_CACHE_SIZE: usize = 256;
+static INTERNED: u8 = 0;
+static NUMBER_FORMAT: u8 = 0;
+/// Whether [`State::set_number_format`] was called with a custom format by any state
+static NUMBER_FORMAT_SET: core::sync::atomic::AtomicBool =
+    core::sync::atomic::AtomicBool::new(false);
+
+/// The globals defined by the standard libraries, see [`State::globals_summary`]
+const STD_GLOBALS: &[&str] = &[
+    "_G",
+    "_VERSION",
+    "assert",
+    "collectgarbage",
+    "dofile",
+    "error",
+    "getmetatable",
+    "ipairs",
+    "load",
+    "loadfile",
+    "next",
+    "pairs",
+    "pcall",
+    "print",
+    "rawequal",
+    "rawget",
+    "rawlen",
+    "rawset",
+    "require",
+    "select",
+    "setmetatable",
+    "tonumber",
+    "tostring",
+    "type",
+    "warn",
+    "xpcall",
+    "coroutine",
+    "debug",
+    "io",
+    "math",
+    "os",
+    "package",
+    "string",
+    "table",
+    "utf8",
+];
+
+/// Check the bytecode header was produced by the same lua version and number format
+fn check_bytecode_header(bytes: &[u8]) -> Result<()> {
+    let mut header = Vec::with_capacity(31);
+    header.extend_from_slice(LUA_SIGNATURE);
+    header.extend_from_slice(&[0x54, 0]);
+    header.extend_from_slice(b"\x19\x93\r\n\x1a\n");
+    header.extend_from_slice(&[
+        core::mem::size_of::<u32>() as u8,
+        core::mem::size_of::<lua_Integer>() as u8,
+        core::mem::size_of::<lua_Number>() as u8,
+    ]);
+    header.extend_from_slice(&(0x5678 as lua_Integer).to_ne_bytes());
+    header.extend_from_slice(&(370.5 as lua_Number).to_ne_bytes());
+    if bytes.starts_with(&header) {
+        Ok(())
+    } else {
+        Err(Error::runtime("invalid bytecode header"))
+    }
+}
+
 pub(crate) const fn debug_ezlua() -> bool {
     option_env!("DEBUG_EZLUA").is_some()
 }
@@ -127,6 +786,18 @@ pub mod unsafe_impl {
         }
     }
 
+    impl<'a> Drop for BalanceGuard<'a> {
+        fn drop(&mut self) {
+            let delta = self.state.get_top() - self.top;
+            self.state.set_top(self.top + self.delta);
+            #[cfg(feature = "std")]
+            if std::thread::panicking() {
+                return;
+            }
+            debug_assert_eq!(delta, self.delta, "unbalanced stack");
+        }
+    }
+
     impl State {
         pub unsafe fn from_raw_state(state: *mut lua_State) -> Self {
             let base = lua_gettop(state);
@@ -161,7 +832,7 @@ pub mod unsafe_impl {
             if UnsafeLuaApi::check_stack(self, n) {
                 Ok(())
             } else {
-                Err(Error::runtime(format!("check stack {n}")))
+                Err(Error::StackOverflow { requested: n })
             }
         }
 
@@ -292,8 +963,13 @@ pub mod unsafe_impl {
         }
 
         /// Create a new lua value, return as [`Value`] rather than [`ValRef`]
+        ///
+        /// The scalar values such as integer, number, boolean and nil never touch the lua stack
         #[inline(always)]
         pub fn new_value<V: ToLua>(&self, val: V) -> Result<Value> {
+            if let Some(scalar) = V::__SCALAR {
+                return Ok(scalar(val));
+            }
             self.new_val(val).map(ValRef::into_value)
         }
 
@@ -325,7 +1001,8 @@ pub mod unsafe_impl {
             Ok(self.top_val().try_into().expect("string"))
         }
 
-        /// Load script string or bytecode
+        /// Load script string or bytecode, see [`Self::load_with_mode`] to reject the bytecode
+        /// which is not verified by lua and can crash the process if malicious
         pub fn load<S: AsRef<[u8]>>(&self, s: S, name: Option<&str>) -> Result<Function> {
             self.check_stack(2)?;
             let guard = self.stack_guard();
@@ -334,13 +1011,86 @@ pub mod unsafe_impl {
             Ok(self.top_val().try_into().expect("function"))
         }
 
+        /// Load a chunk whose `_ENV` is `env`, the global variables of the chunk are accessed from `env`
+        /// instead of the real global table
+        pub fn load_with_env<S: AsRef<[u8]>>(
+            &self,
+            s: S,
+            name: Option<&str>,
+            env: Table,
+        ) -> Result<Function> {
+            let func = self.load(s, name)?;
+            match func.get_upvalue_name(1)? {
+                Some((_, "_ENV")) => func.set_upvalue(1, env)?,
+                _ => return Err(Error::runtime("the first upvalue of chunk is not _ENV")),
+            }
+            Ok(func)
+        }
+
+        /// Load the chunk only if its kind is allowed by `mode`, otherwise [`Error::Syntax`] is returned
+        pub fn load_with_mode<S: AsRef<[u8]>>(
+            &self,
+            s: S,
+            name: Option<&str>,
+            mode: LoadMode,
+        ) -> Result<Function> {
+            self.check_stack(2)?;
+            let guard = self.stack_guard();
+            self.statuscode_to_error(self.load_bufferx(
+                s.as_ref(),
+                name.unwrap_or("?"),
+                mode.as_str(),
+            ))?;
+            core::mem::forget(guard);
+            Ok(self.top_val().try_into().expect("function"))
+        }
+
+        /// Load a batch of `(name, script)` chunks, the failed ones don't stop loading the others
+        pub fn load_all<'a>(
+            &self,
+            chunks: impl IntoIterator<Item = (&'a str, &'a str)>,
+        ) -> Vec<(String, Result<Function>)> {
+            chunks
+                .into_iter()
+                .map(|(name, script)| (name.into(), self.load(script, Some(name))))
+                .collect()
+        }
+
+        /// Allow or disallow [`Self::load_binary_verified`] to load bytecode, it's disallowed by default
+        pub fn set_bytecode_trusted(&self, trusted: bool) -> Result<()> {
+            self.registry()
+                .setp(&TRUSTED_BYTECODE, trusted.then_some(true))
+        }
+
+        /// Load precompiled bytecode, only if it's allowed by [`Self::set_bytecode_trusted`]
+        /// and the header matches current lua version.
+        ///
+        /// **WARNING**: lua doesn't verify the bytecode, malicious bytecode can crash the process
+        /// or corrupt the memory. Never load bytecode from untrusted sources, passing the header
+        /// check doesn't make it safe
+        pub fn load_binary_verified(&self, bytes: &[u8]) -> Result<Function> {
+            self.check_stack(2)?;
+            self.raw_getp(LUA_REGISTRYINDEX, &TRUSTED_BYTECODE);
+            let trusted = self.to_bool(-1);
+            self.pop(1);
+            if !trusted {
+                return Err(Error::runtime("loading bytecode is not trusted"));
+            }
+            check_bytecode_header(bytes)?;
+
+            let guard = self.stack_guard();
+            self.statuscode_to_error(self.load_bufferx(bytes, "=(bytecode)", "b"))?;
+            core::mem::forget(guard);
+            Ok(self.top_val().try_into().expect("function"))
+        }
+
         /// Create function from script file
         #[cfg(feature = "std")]
         #[inline]
         pub fn load_file<P: AsRef<Path>>(&self, path: P) -> Result<Function> {
             let path = path.as_ref();
             self.load(
-                std::fs::read(path).map_err(Error::from_debug)?,
+                std::fs::read(path)?,
                 Some(format!("@{}", path.to_string_lossy()).as_str()),
             )
         }
@@ -363,6 +1113,97 @@ pub mod unsafe_impl {
             Ok(())
         }
 
+        /// Register a lua module like [`Self::register_module`], but `init` is installed into `package.preload`
+        /// and not called until the module is required by lua at the first time
+        pub fn register_module_lazy(
+            &self,
+            name: &str,
+            init: impl for<'a> Fn(&'a State) -> Result<Table<'a>> + 'static,
+        ) -> Result<()> {
+            self.check_stack(2)?;
+            self.get_subtable(LUA_REGISTRYINDEX, crate::cstr!("_PRELOAD"));
+            let preload: Table = self.top_val().try_into()?;
+            preload.raw_set(name, self.new_function(move |s, ()| init(s))?)
+        }
+
+        /// Open a single standard library, and set it as global variable
+        pub fn open_std_lib(&self, lib: StdLib) -> Result<()> {
+            self.check_stack(3)?;
+            let _guard = self.stack_guard();
+            self.requiref(lib.name(), lib.opener(), true);
+            Ok(())
+        }
+
+        /// Open the standard libraries in the set, the others are left untouched
+        #[cfg(feature = "bitflags")]
+        pub fn open_std_libs(&self, libs: StdLibSet) -> Result<()> {
+            for lib in StdLib::ALL {
+                if libs.contains(lib.into()) {
+                    self.open_std_lib(lib)?;
+                }
+            }
+            Ok(())
+        }
+
+        /// Enable or disable the caching of `require`, when disabled, the modules in `package.preload`
+        /// or `package.path` will be re-executed each time they are required
+        pub fn set_require_caching(&self, enabled: bool) -> Result<()> {
+            static ORIGIN_REQUIRE: u8 = 0;
+
+            let g = self.global();
+            let reg = self.registry();
+            let origin = reg.getp(&ORIGIN_REQUIRE)?;
+            if enabled {
+                if !origin.is_nil() {
+                    g.set("require", origin)?;
+                    reg.setp(&ORIGIN_REQUIRE, ())?;
+                }
+            } else if origin.is_nil() {
+                let require = g.get("require")?;
+                reg.setp(&ORIGIN_REQUIRE, &require)?;
+                let wrapper = self
+                    .load(
+                        r#"
+                        local require, loaded = ...
+                        local package = loaded.package
+                        return function(name, ...)
+                            if package.preload[name] or package.searchpath(name, package.path) then
+                                loaded[name] = nil
+                            end
+                            return require(name, ...)
+                        end
+                        "#,
+                        Some("=require"),
+                    )?
+                    .pcall::<_, Function>((require, reg.get("_LOADED")?))?;
+                g.set("require", wrapper)?;
+            }
+            Ok(())
+        }
+
+        /// Enable or disable prefixing the argument conversion errors with the location of the lua caller,
+        /// like `script.lua:42: `, it's disabled by default
+        pub fn set_arg_error_location(&self, enabled: bool) -> Result<()> {
+            self.registry()
+                .setp(&ARG_ERROR_LOCATION, enabled.then_some(true))
+        }
+
+        pub(crate) fn arg_error_location(&self) -> String {
+            if self.check_stack(1).is_err() {
+                return String::new();
+            }
+            self.raw_getp(LUA_REGISTRYINDEX, &ARG_ERROR_LOCATION);
+            let enabled = self.to_bool(-1);
+            self.pop(1);
+            if !enabled {
+                return String::new();
+            }
+            self.location(1);
+            let result = self.to_string_lossy(-1).unwrap_or_default().into_owned();
+            self.pop(1);
+            result
+        }
+
         /// Get the lua global table
         pub fn global(&self) -> Table {
             self.check_stack(1).expect("stack");
@@ -370,6 +1211,17 @@ pub mod unsafe_impl {
             self.top_val().try_into().expect("global table")
         }
 
+        /// Replace a global value temporarily, the old value will be restored when the guard dropped
+        pub fn override_global<V: ToLua>(&self, name: &str, value: V) -> Result<GlobalGuard> {
+            let global = self.global();
+            let old = self.registry_value(global.get(name)?)?;
+            global.set(name, value)?;
+            Ok(GlobalGuard {
+                name: name.into(),
+                old,
+            })
+        }
+
         pub fn main_state(&self) -> LuaThread {
             self.check_stack(1).expect("stack");
             self.raw_geti(LUA_REGISTRYINDEX, LUA_RIDX_MAINTHREAD);
@@ -385,6 +1237,14 @@ pub mod unsafe_impl {
 
         /// Do a full GC for lua
         pub fn gc_collect(&self) -> Result<()> {
+            // the free slots still hold the values dropped in rust, clear them to make these collectable
+            let top = self.get_top();
+            if self.check_stack(1).is_ok() {
+                for &i in self.free.borrow().iter().filter(|&&i| i <= top) {
+                    self.push_nil();
+                    self.replace(i);
+                }
+            }
             self.gc(GcOption::Collect, 0);
 
             Ok(())
@@ -421,7 +1281,9 @@ pub mod unsafe_impl {
                 lua_pushboolean(l, lua_gc(l, LUA_GCSTEP, lua_tointeger(l, 1) as i32));
                 1
             }
-            self.protect_call(kbytes, protect)
+            let result = self.protect_call(kbytes, protect)?;
+            self.notify_gc_observer(crate::gc::GcPhase::Step);
+            Ok(result)
         }
 
         /// Sets the 'pause' value of the collector.
@@ -481,6 +1343,47 @@ pub mod unsafe_impl {
             Ok(result)
         }
 
+        /// Structured call stack info, from the function at `level`.
+        /// If `skip_c` is true, the C function frames (including rust functions) are excluded
+        pub fn frames(&self, level: i32, skip_c: bool) -> Vec<StackFrame> {
+            let mut result = Vec::new();
+            let mut level = level;
+            let thread = self.thread_name();
+            while let Some(mut ar) = self.get_stack(level) {
+                level += 1;
+                self.get_info(crate::cstr!("Sln"), &mut ar);
+                let what = ar.what().unwrap_or_default();
+                if skip_c && what == "C" {
+                    continue;
+                }
+                result.push(StackFrame {
+                    source: ar.short_src().into_owned(),
+                    line: ar.currentline,
+                    line_defined: ar.linedefined,
+                    name: ar.name().map(Into::into),
+                    namewhat: ar.namewhat().unwrap_or_default().into_owned(),
+                    what: what.into_owned(),
+                    thread: thread.clone(),
+                });
+            }
+            result
+        }
+
+        /// Like [`State::backtrace`] for current thread, but build the traceback from [`State::frames`]
+        pub fn traceback_filtered(&self, msg: &str, level: i32, skip_c: bool) -> String {
+            use core::fmt::Write;
+
+            let mut result = String::from(msg);
+            if !result.is_empty() {
+                result.push('\n');
+            }
+            result.push_str("stack traceback:");
+            for frame in self.frames(level, skip_c) {
+                let _ = write!(result, "\n\t{frame}");
+            }
+            result
+        }
+
         /// [-0, +1, -]
         pub(crate) fn get_or_init_metatable(&self, callback: MetatableKey) -> Result<()> {
             let top = self.get_top();
@@ -590,11 +1493,19 @@ pub mod unsafe_impl {
             let guard = self.stack_guard();
 
             self.check_stack(args.value_count().unwrap_or(10) as i32 + 2)?;
-            self.push_fn(Some(Self::traceback_c));
+            // keep the placeholder of message handler even if traceback is disabled
+            let msgh = if self.raw_getp(LUA_REGISTRYINDEX, &NO_TRACEBACK) == Type::Nil {
+                self.pop(1);
+                self.push_fn(Some(Self::traceback_c));
+                guard.top() + 1
+            } else {
+                0
+            };
             self.push(func)?;
             self.statuscode_to_error(unsafe {
-                lua_pcall(self.state, self.push_multi(args)? as _, -1, guard.top() + 1)
-            })?;
+                lua_pcall(self.state, self.push_multi(args)? as _, -1, msgh)
+            })
+            .inspect_err(|err| self.record_error(err))?;
 
             let result_base = guard.top() + 2;
             self.to_multi_balance(guard, result_base)
@@ -637,6 +1548,13 @@ pub mod unsafe_impl {
             self.error_string(format!("{e:?}"))
         }
 
+        /// Whether to append the stack traceback to the error message in [`Function::pcall`],
+        /// it's enabled by default, disable it to save the cost of building traceback
+        pub fn set_capture_traceback(&self, enabled: bool) -> Result<()> {
+            self.registry()
+                .setp(&NO_TRACEBACK, (!enabled).then_some(true))
+        }
+
         pub unsafe extern "C-unwind" fn traceback_c(l: *mut lua_State) -> i32 {
             luaL_traceback(l, l, lua_tostring(l, 1), 1);
             1
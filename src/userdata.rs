@@ -1,6 +1,6 @@
 //! Implementation to userdata binding
 
-use alloc::{boxed::Box, format};
+use alloc::{boxed::Box, format, string::String};
 use core::{
     cell::{Ref, RefCell, RefMut},
     ffi::c_int,
@@ -22,7 +22,7 @@ use crate::{
     value::*,
 };
 
-unsafe fn get_weak_meta(s: &State) -> Result<()> {
+pub(crate) unsafe fn get_weak_meta(s: &State) -> Result<()> {
     use crate::luaapi::UnsafeLuaApi;
 
     let top = s.get_top();
@@ -56,6 +56,17 @@ pub trait UserDataTrans<T: UserData>: Sized {
     const INTO_INNER: fn(Self) -> T;
 
     fn read(&self) -> Self::Read<'_>;
+
+    /// Read the inner value, `None` if it's mutably borrowed currently
+    fn try_read(&self) -> Option<Self::Read<'_>> {
+        Some(self.read())
+    }
+
+    /// Replace the inner value through the shared reference, `new` is given back if it's borrowed currently,
+    /// or the replacement can't be checked for this kind of storage
+    fn try_replace(&self, new: T) -> core::result::Result<T, T> {
+        Err(new)
+    }
 }
 
 impl<T: UserData> UserDataTrans<T> for T {
@@ -92,6 +103,17 @@ impl<T: UserData> UserDataTrans<T> for RefCell<T> {
     fn read(&self) -> Self::Read<'_> {
         self.borrow()
     }
+
+    fn try_read(&self) -> Option<Self::Read<'_>> {
+        self.try_borrow().ok()
+    }
+
+    fn try_replace(&self, new: T) -> core::result::Result<T, T> {
+        match self.try_borrow_mut() {
+            Ok(mut this) => Ok(core::mem::replace(&mut *this, new)),
+            Err(_) => Err(new),
+        }
+    }
 }
 
 impl<'a, T: UserData<Trans = RefCell<T>>> FromLua<'a> for &'a RefCell<T> {
@@ -151,6 +173,17 @@ impl<T: UserData> UserDataTrans<T> for parking_lot::RwLock<T> {
     fn read(&self) -> Self::Read<'_> {
         self.try_read().expect("")
     }
+
+    fn try_read(&self) -> Option<Self::Read<'_>> {
+        parking_lot::RwLock::try_read(self)
+    }
+
+    fn try_replace(&self, new: T) -> core::result::Result<T, T> {
+        match self.try_write() {
+            Some(mut this) => Ok(core::mem::replace(&mut *this, new)),
+            None => Err(new),
+        }
+    }
 }
 
 #[cfg(feature = "parking_lot")]
@@ -271,6 +304,10 @@ pub fn init_wrapper<U: UserData>(mt: &Table) -> Result<()> {
         mt.setf(crate::cstr!("__len"), __len as CFunction)?;
     }
 
+    if U::TO_STRING.is_some() {
+        mt.setf(crate::cstr!("__tostring"), __tostring::<U> as CFunction)?;
+    }
+
     {
         let setter = mt.state.new_table_with_size(0, 0)?;
         mt.set("__setter", setter.clone())?;
@@ -371,6 +408,9 @@ pub trait UserData: Sized {
     /// whether raising error when accessing non-exists property
     const ACCESS_ERROR: bool = true;
 
+    /// set the `__tostring` metamethod if provided, otherwise lua formats it with `__name`
+    const TO_STRING: Option<fn(&Self) -> String> = None;
+
     const METATABLE_KEY: MetatableKey = init_wrapper::<Self>;
 
     fn metatable_key() -> MetatableKey {
@@ -528,6 +568,31 @@ unsafe extern "C-unwind" fn __len(l: *mut lua_State) -> c_int {
     1
 }
 
+unsafe extern "C-unwind" fn __tostring<U: UserData>(l: *mut lua_State) -> c_int {
+    use crate::luaapi::UnsafeLuaApi;
+
+    let s = State::from_raw_state(l);
+    let text = LuaUserData::try_from(s.val(1))
+        .ok()
+        .and_then(|u| {
+            let this = u.userdata_ref::<U>()?;
+            // it may be mutably borrowed by the method calling tostring
+            let text = match this.try_read() {
+                Some(this) => U::TO_STRING.map(|f| f(&this)).ok_or("userdata not match"),
+                None => Err("userdata already mutably borrowed"),
+            };
+            Some(text)
+        })
+        .unwrap_or(Err("userdata not match"));
+    match text {
+        Ok(text) => {
+            s.push_string(&text);
+            1
+        }
+        Err(msg) => s.error_string(msg),
+    }
+}
+
 fn init_userdata<T: UserData>(s: &State) -> Result<()> {
     use crate::luaapi::UnsafeLuaApi;
 
@@ -720,6 +785,31 @@ impl State {
     }
 }
 
+const METAMETHODS: &[&str] = &[
+    "__add",
+    "__sub",
+    "__mul",
+    "__div",
+    "__mod",
+    "__pow",
+    "__unm",
+    "__idiv",
+    "__band",
+    "__bor",
+    "__bxor",
+    "__shl",
+    "__shr",
+    "__bnot",
+    "__concat",
+    "__len",
+    "__eq",
+    "__lt",
+    "__le",
+    "__call",
+    "__tostring",
+    "__pairs",
+];
+
 #[derive(Clone, Copy, Debug)]
 pub struct MethodRegistry<'a, U: 'a, R, W>(pub &'a Table<'a>, PhantomData<(U, R, W)>);
 
@@ -917,6 +1007,71 @@ impl<'a, U: 'a, R: 'a, W> MethodRegistry<'a, U, R, W> {
         Ok(self)
     }
 
+    /// Add an async function without receiver, like a static factory function of this type
+    #[cfg(feature = "async")]
+    #[inline(always)]
+    pub fn add_async<K, F, ARGS, RET, FUT>(&self, k: K, f: F) -> Result<&Self>
+    where
+        K: ToLua,
+        ARGS: FromLuaMulti<'a> + Send + 'a,
+        RET: ToLuaMulti + 'a,
+        F: Fn(&'a State, ARGS) -> FUT + Send + 'static,
+        FUT: core::future::Future<Output = RET> + Send + 'a,
+    {
+        self.0.raw_set(
+            k,
+            self.state.bind_async_closure(move |lua, base| {
+                Ok(f(lua, ARGS::from_lua_multi(lua, base)?))
+            })?,
+        )?;
+        Ok(self)
+    }
+
+    /// Add a metamethod like `__add`, `__eq` or `__lt` in [`UserData::metatable`], the unknown names are rejected.
+    ///
+    /// Lua passes the operands of binary operators in order, `this` is the left one,
+    /// so `1 + ud` raises a conversion error unless the number is accepted as `this`
+    #[inline(always)]
+    pub fn add_meta_method<M, ARGS, RET>(&self, name: &str, method: M) -> Result<&Self>
+    where
+        ARGS: FromLuaMulti<'a> + 'a,
+        RET: ToLuaMulti + 'a,
+        M: Fn(&'a State, &'a U, ARGS) -> RET,
+        R: Deref<Target = U> + FromLua<'a> + 'a,
+    {
+        if !METAMETHODS.contains(&name) {
+            return Err(Error::runtime(format!("unknown metamethod: {name}")));
+        }
+        self.add_method(name, method)
+    }
+
+    /// Set the `__call` metamethod in [`UserData::metatable`], so the userdata can be called like a function,
+    /// `method` receives the userdata and then the call arguments
+    #[inline(always)]
+    pub fn add_call<M, ARGS, RET>(&self, method: M) -> Result<&Self>
+    where
+        ARGS: FromLuaMulti<'a> + 'a,
+        RET: ToLuaMulti + 'a,
+        M: Fn(&'a State, &'a U, ARGS) -> RET,
+        R: Deref<Target = U> + FromLua<'a> + 'a,
+    {
+        self.add_method("__call", method)
+    }
+
+    /// Set the `__pairs` metamethod in [`UserData::metatable`] to make `pairs(ud)` work,
+    /// `method` returns the iterator function, state and initial control value, or a [`StaticIter`](crate::marker::StaticIter).
+    ///
+    /// Only the returned iterator is traversed, the fields stored in the uservalue by [`UserData::INDEX_USERVALUE`] are not included
+    #[inline(always)]
+    pub fn add_pairs<M, RET>(&self, method: M) -> Result<&Self>
+    where
+        RET: ToLuaMulti + 'a,
+        M: Fn(&'a State, &'a U) -> RET,
+        R: Deref<Target = U> + FromLua<'a> + 'a,
+    {
+        self.add_method("__pairs", move |lua, this, ()| method(lua, this))
+    }
+
     #[inline(always)]
     pub fn add<K, V, ARGS: 'a, RET: 'a>(&self, k: K, v: V) -> Result<&Self>
     where
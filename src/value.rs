@@ -1,19 +1,20 @@
 //! Implementation to lua value
 
-use alloc::{borrow::Cow, vec::Vec};
-use core::ffi::{c_char, c_void};
+use alloc::{borrow::Cow, collections::BTreeMap, format, string::String, sync::Arc, vec::Vec};
+use core::ffi::{c_char, c_int, c_void};
 use core::ops;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use crate::{
     convert::*,
     error::*,
     ffi::{self, lua_Integer, lua_Number, lua_tostring},
-    luaapi::{Reference, Type, UnsafeLuaApi},
+    luaapi::{Comparison, Reference, ThreadStatus, Type, UnsafeLuaApi},
     marker::RegVal,
     prelude::ArgRef,
     state::*,
     str::CStr,
-    userdata::UserData,
+    userdata::{UserData, UserDataTrans},
 };
 
 /// Value reference on the lua stack
@@ -152,8 +153,15 @@ impl<'a> ValRef<'a> {
         unsafe { lua_tostring(self.state.state, self.index) }
     }
 
-    /// Call `tostring` if this value is not a string
+    /// Call `tostring` if this value is not a string,
+    /// the float numbers are formatted by [`State::number_format`] if a custom format was set
     pub fn tostring(&self) -> Cow<str> {
+        if self.type_of() == Type::Number && !self.state.is_integer(self.index) {
+            let fmt = self.state.number_format();
+            if fmt != NumberFormat::Lua {
+                return fmt.format(self.to_number()).into();
+            }
+        }
         self.to_string_lossy().unwrap_or_else(|| {
             self.state
                 .global()
@@ -197,6 +205,14 @@ impl<'a> ValRef<'a> {
         self.clone().cast_into()
     }
 
+    /// Get the pointer if this value is a light userdata, note that the pointee type `T`
+    /// can not be verified, since light userdata carries no type information
+    #[inline]
+    pub fn as_light_userdata<T>(&self) -> Option<*mut T> {
+        (self.type_of() == Type::LightUserdata)
+            .then(|| self.state.to_userdata(self.index) as *mut T)
+    }
+
     pub(crate) fn getf(&self, k: &CStr) -> ValRef {
         self.state.check_stack(1).expect("stack");
         self.state.get_field(self.index, k);
@@ -304,6 +320,15 @@ impl<'a> ValRef<'a> {
         self.state.pcall_trace(ArgRef(self.index), args)
     }
 
+    /// Like `pcall()`, but the traceback of the runtime error is separated into [`TracedError`]
+    #[inline]
+    pub fn pcall_traced<T: ToLuaMulti, R: FromLuaMulti<'a>>(
+        &self,
+        args: T,
+    ) -> core::result::Result<R, TracedError> {
+        self.pcall(args).map_err(TracedError::from)
+    }
+
     /// Invoke `pcall()` without return value
     #[inline(always)]
     pub fn pcall_void<T: ToLuaMulti>(&self, args: T) -> Result<()> {
@@ -445,6 +470,24 @@ impl<'a> ValRef<'a> {
     }
 }
 
+/// Index of the array part of table, which accepts both `usize` and lua integer, see [`Table::raw_insert`]
+pub trait ArrayIndex: Copy {
+    fn to_lua_index(self) -> Result<lua_Integer>;
+}
+
+macro_rules! impl_array_index {
+    ($($t:ty)*) => {$(
+        impl ArrayIndex for $t {
+            #[inline(always)]
+            fn to_lua_index(self) -> Result<lua_Integer> {
+                lua_Integer::try_from(self).map_err(|_| Error::runtime("index out of range"))
+            }
+        }
+    )*};
+}
+
+impl_array_index!(i32 u32 i64 u64 isize usize);
+
 /// Iterator for table traversing, like `pairs` in lua
 pub struct TableIter<'a, V: AsRef<Table<'a>>> {
     val: V,
@@ -496,9 +539,106 @@ unsafe impl Send for Value<'_> {}
 unsafe impl Sync for Value<'_> {}
 
 impl<'a> Value<'a> {
+    /// Name of the value type, distinguishing integer from float number unlike [`Type`]
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Nil => "nil",
+            Self::Bool(_) => "boolean",
+            Self::Integer(_) => "integer",
+            Self::Number(_) => "number",
+            Self::LightUserdata(_) => "lightuserdata",
+            Self::String(_) => "string",
+            Self::Table(_) => "table",
+            Self::Function(_) => "function",
+            Self::UserData(_) => "userdata",
+            Self::Thread(_) => "thread",
+        }
+    }
+
     pub fn light_userdata<T: Sized>(p: *const T) -> Self {
         Value::LightUserdata(p as usize as _)
     }
+
+    /// Borrow the table if this value is a table
+    pub fn as_table(&self) -> Option<&Table<'a>> {
+        match self {
+            Self::Table(val) => Some(val),
+            _ => None,
+        }
+    }
+
+    /// Convert to the table if this value is a table
+    pub fn into_table(self) -> Option<Table<'a>> {
+        match self {
+            Self::Table(val) => Some(val),
+            _ => None,
+        }
+    }
+
+    /// Borrow the function if this value is a function
+    pub fn as_function(&self) -> Option<&Function<'a>> {
+        match self {
+            Self::Function(val) => Some(val),
+            _ => None,
+        }
+    }
+
+    /// Convert to the function if this value is a function
+    pub fn into_function(self) -> Option<Function<'a>> {
+        match self {
+            Self::Function(val) => Some(val),
+            _ => None,
+        }
+    }
+
+    /// Borrow the string if this value is a string
+    pub fn as_string(&self) -> Option<&LuaString<'a>> {
+        match self {
+            Self::String(val) => Some(val),
+            _ => None,
+        }
+    }
+
+    /// Convert to the string if this value is a string
+    pub fn into_string(self) -> Option<LuaString<'a>> {
+        match self {
+            Self::String(val) => Some(val),
+            _ => None,
+        }
+    }
+
+    /// Borrow the userdata if this value is a userdata
+    pub fn as_userdata(&self) -> Option<&LuaUserData<'a>> {
+        match self {
+            Self::UserData(val) => Some(val),
+            _ => None,
+        }
+    }
+
+    /// Convert to the userdata if this value is a userdata
+    pub fn into_userdata(self) -> Option<LuaUserData<'a>> {
+        match self {
+            Self::UserData(val) => Some(val),
+            _ => None,
+        }
+    }
+
+    /// Borrow the thread if this value is a thread
+    pub fn as_thread(&self) -> Option<&LuaThread<'a>> {
+        match self {
+            Self::Thread(val) => Some(val),
+            _ => None,
+        }
+    }
+
+    /// Convert to the thread if this value is a thread
+    pub fn into_thread(self) -> Option<LuaThread<'a>> {
+        match self {
+            Self::Thread(val) => Some(val),
+            _ => None,
+        }
+    }
 }
 
 /// Represents a lua table on the stack
@@ -517,6 +657,15 @@ pub struct LuaString<'l>(pub(crate) ValRef<'l>);
 #[derive(Debug, Clone, derive_more::Deref)]
 pub struct LuaThread<'l>(pub(crate) ValRef<'l>);
 
+/// Result of [`LuaThread::resume`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoResult<R> {
+    /// The coroutine yielded these values, and can be resumed again
+    Yielded(R),
+    /// The coroutine finished with these values
+    Returned(R),
+}
+
 /// Represents a lua userdata on the stack
 #[derive(Debug, Clone, derive_more::Deref)]
 pub struct LuaUserData<'l>(pub(crate) ValRef<'l>);
@@ -581,7 +730,7 @@ impl_wrap!(LuaUserData<'a>, Type::Userdata, as_userdata);
 impl<'l> Table<'l> {
     /// Get value with a lightuserdata key, commonly is a function pointer
     #[inline]
-    pub fn getp<T>(&self, p: *const T) -> Result<ValRef> {
+    pub fn getp<T>(&self, p: *const T) -> Result<ValRef<'l>> {
         self.state.check_stack(1)?;
         self.state.raw_getp(self.index, p);
         Ok(self.state.top_val())
@@ -608,6 +757,37 @@ impl<'l> Table<'l> {
         self.state.unreference(self.index, r);
     }
 
+    /// Collect the entries into a map of [`Value`], useful to decode an options table of mixed types,
+    /// an error is returned if any key is not a string
+    #[cfg(feature = "std")]
+    pub fn to_value_map(&self) -> Result<std::collections::HashMap<String, Value<'l>>> {
+        let mut result = std::collections::HashMap::with_capacity(self.entry_count());
+        for (k, v) in self.iter()? {
+            let k = k
+                .to_str()
+                .ok_or_else(|| Error::convert(format!("expect string key, got {}", k.type_of())))?
+                .into();
+            result.insert(k, v.into_value());
+        }
+        Ok(result)
+    }
+
+    /// Keep only the entries for which `f` returns true, the others are removed by raw assignment.
+    /// The keys to remove are collected during the traversal and removed after it
+    pub fn retain(&self, mut f: impl FnMut(&ValRef, &ValRef) -> bool) -> Result<()> {
+        // collect to a table rather than the stack, which can't hold too many values
+        let removed = self.state.new_table()?;
+        for (k, v) in self.iter()? {
+            if !f(&k, &v) {
+                removed.push(k)?;
+            }
+        }
+        for i in 1..=removed.raw_len_i64() {
+            self.raw_set(removed.raw_geti(i)?, ())?;
+        }
+        Ok(())
+    }
+
     /// Count of the table entries
     pub fn entry_count(&self) -> usize {
         let mut count = 0usize;
@@ -619,6 +799,77 @@ impl<'l> Table<'l> {
         count
     }
 
+    /// Remove all the entries by raw assignment without triggering `__newindex`,
+    /// the allocated capacity is kept, it's only shrunk when lua rehashes the table on later insertion
+    pub fn clear(&self) -> Result<()> {
+        self.state.check_stack(4)?;
+        self.state.push_nil();
+        // assigning nil to an existing field is allowed during the traversal
+        while self.state.next(self.index) {
+            self.state.pop(1);
+            self.state.push_value(-1);
+            self.state.push_nil();
+            self.state.raw_set(self.index);
+        }
+        Ok(())
+    }
+
+    /// Count of the sequence part and the other entries, as `(array_len, hash_count)`
+    pub fn part_sizes(&self) -> Result<(usize, usize)> {
+        let array_len = self.raw_len();
+        let mut hash_count = 0usize;
+        self.state.check_stack(3)?;
+        self.state.push_nil();
+        while self.state.next(self.index) {
+            let in_array = self.state.is_integer(-2)
+                && (1..=array_len as lua_Integer).contains(&self.state.to_integer(-2));
+            if !in_array {
+                hash_count += 1;
+            }
+            self.state.pop(1);
+        }
+        Ok((array_len, hash_count))
+    }
+
+    /// Replace each value of the table by the result of `f` in place, without metamethod triggers
+    pub fn map_values<F: FnMut(&'l State, ValRef<'l>) -> Result<Value<'l>>>(
+        &self,
+        mut f: F,
+    ) -> Result<()> {
+        // collect the keys to a table first, assigning new fields while traversing by `lua_next` is not allowed,
+        // and the stack can't hold too many keys
+        let keys = self.state.new_table()?;
+        for (k, _) in self.iter()? {
+            keys.push(k)?;
+        }
+        for i in 1..=keys.raw_len_i64() {
+            let key = keys.raw_geti(i)?;
+            let val = f(self.state, self.raw_get(&key)?)?;
+            self.raw_set(key, val)?;
+        }
+        Ok(())
+    }
+
+    /// Create a new table merged from `defaults` and this table, the fields of this table take precedence,
+    /// and the nested tables presenting in both are merged recursively
+    pub fn with_defaults(&self, defaults: &Table) -> Result<Table<'l>> {
+        let res = self.state.new_table()?;
+        res.merge(defaults)?;
+        res.merge(self)?;
+        Ok(res)
+    }
+
+    fn merge(&self, other: &Table) -> Result<()> {
+        for (k, v) in other.iter()? {
+            let old = self.raw_get(&k)?;
+            match (Table::try_from(v.clone()), Table::try_from(old)) {
+                (Ok(v), Ok(old)) => self.raw_set(k, v.with_defaults(&old)?)?,
+                _ => self.raw_set(k, v)?,
+            }
+        }
+        Ok(())
+    }
+
     /// Iterator to the table entries
     pub fn iter<'t>(&'t self) -> Result<TableIter<'l, &'t Self>> {
         Ok(TableIter {
@@ -653,6 +904,54 @@ impl<'l> Table<'l> {
         Ok(())
     }
 
+    /// Read the values in index range `start..=end` without metamethod triggers, like `table.unpack(t, start, end)`,
+    /// an inverted range results empty, and a range with too many values results an error
+    pub fn read_range<V: FromLua<'l> + 'static>(
+        &self,
+        start: lua_Integer,
+        end: lua_Integer,
+    ) -> Result<Vec<V>> {
+        if start > end {
+            return Ok(Vec::new());
+        }
+        let count = end
+            .checked_sub(start)
+            .and_then(|n| n.checked_add(1))
+            .filter(|&n| n <= c_int::MAX as lua_Integer)
+            .ok_or_else(|| Error::runtime("too many results to read"))?;
+        let mut result = Vec::with_capacity((count as usize).min(self.raw_len() + 1));
+        self.state.check_stack(2)?;
+        for i in start..=end {
+            self.state.raw_geti(self.index, i);
+            result.push(self.state.top_val().cast_into::<V>()?);
+        }
+        Ok(result)
+    }
+
+    /// Read the array part as numbers, faster than `Vec<f64>::from_lua` by skipping the generic conversion
+    pub fn collect_numbers(&self) -> Result<Vec<lua_Number>> {
+        self.collect_array(|s| s.to_numberx(-1), "number")
+    }
+
+    /// Read the array part as integers, like [`Self::collect_numbers`]
+    pub fn collect_integers(&self) -> Result<Vec<lua_Integer>> {
+        self.collect_array(|s| s.to_integerx(-1), "integer")
+    }
+
+    fn collect_array<T>(&self, read: impl Fn(&State) -> Option<T>, expect: &str) -> Result<Vec<T>> {
+        let len = self.raw_len();
+        let mut result = Vec::with_capacity(len);
+        self.state.check_stack(1)?;
+        for i in 1..=len as lua_Integer {
+            self.state.raw_geti(self.index, i);
+            let val = read(self.state);
+            self.state.pop(1);
+            result
+                .push(val.ok_or_else(|| Error::convert(format!("expect {expect} at index {i}")))?);
+        }
+        Ok(result)
+    }
+
     pub fn take_reference(&self, r: Reference) -> Result<ValRef<'l>> {
         let res = self.raw_geti(r.0)?;
         self.unreference(r);
@@ -669,6 +968,19 @@ impl<'l> Table<'l> {
         Ok(self.state.top_val())
     }
 
+    /// Test whether the key exists without metamethod triggers, a key with nil value doesn't exist in lua
+    pub fn contains_key<K: ToLua>(&self, key: K) -> Result<bool> {
+        self.state.check_stack(2)?;
+        self.state.push(key)?;
+        if self.state.is_nil(-1) {
+            self.state.pop(1);
+            return Ok(false);
+        }
+        let ty = self.state.raw_get(self.index);
+        self.state.pop(1);
+        Ok(ty != Type::Nil)
+    }
+
     /// Set value by any key without metamethod triggers
     #[inline]
     pub fn raw_set<K: ToLua, V: ToLua>(&self, k: K, v: V) -> Result<()> {
@@ -680,25 +992,93 @@ impl<'l> Table<'l> {
         Ok(())
     }
 
-    /// Insert an element into the array table, equivalent to `table.insert` in lua
+    /// Get length of the array part as lua integer without metamethod triggers,
+    /// the index type used by [`Self::raw_geti`] and [`Self::raw_seti`]
+    #[inline]
+    pub fn raw_len_i64(&self) -> lua_Integer {
+        // not through `usize`, which is truncated on 32-bit targets
+        unsafe { ffi::lua_rawlen(self.state.state, self.index) as lua_Integer }
+    }
+
+    /// Insert an element into the array table, equivalent to `table.insert` in lua,
+    /// `i` can be either `usize` or lua integer
     #[inline(always)]
-    pub fn raw_insert<V: ToLua>(&self, i: usize, val: V) -> Result<()> {
+    pub fn raw_insert<V: ToLua>(&self, i: impl ArrayIndex, val: V) -> Result<()> {
+        let i = i.to_lua_index()?;
         self.raw_move_vals(i)?;
-        self.raw_seti(i as i64, val)
+        self.raw_seti(i, val)
     }
 
     #[doc(hidden)]
-    pub fn raw_move_vals(&self, i: usize) -> Result<()> {
-        for i in i..=self.raw_len() {
-            self.raw_seti((i + 1) as i64, self.raw_get(i as i64)?)?;
+    pub fn raw_move_vals(&self, i: impl ArrayIndex) -> Result<()> {
+        let i = i.to_lua_index()?;
+        let mut j = self.raw_len_i64();
+        while j >= i {
+            self.raw_seti(j + 1, self.raw_get(j)?)?;
+            j -= 1;
         }
         Ok(())
     }
 
-    /// Push an element to end of the array part of a table, alias to `self.raw_seti((self.raw_len() + 1) as i64, val)`
+    /// Sort the elements `1..=raw_len()` in place, ascending by `<` if `cmp` is `None`,
+    /// otherwise `cmp(a, b)` should return true if `a` must come before `b`.
+    /// The errors raised by the comparison are returned, and the table is left untouched in this case
+    pub fn sort(&self, cmp: Option<Function>) -> Result<()> {
+        let len = self.raw_len_i64();
+        // copy the values to a table rather than the stack, which can't hold too many values
+        let vals = self
+            .state
+            .new_table_with_size(len.min(c_int::MAX as _) as _, 0)?;
+        for i in 1..=len {
+            vals.raw_seti(i, self.raw_geti(i)?)?;
+        }
+        let mut order = (1..=len).collect::<Vec<_>>();
+        merge_sort(&mut order, &mut |&a: &lua_Integer, &b: &lua_Integer| {
+            let (a, b) = (vals.raw_geti(a)?, vals.raw_geti(b)?);
+            match cmp.as_ref() {
+                Some(cmp) => cmp.pcall((&a, &b)),
+                None => a.lt(&b),
+            }
+        })?;
+        for (i, j) in order.into_iter().enumerate() {
+            self.raw_seti(i as lua_Integer + 1, vals.raw_geti(j)?)?;
+        }
+        Ok(())
+    }
+
+    /// Remove the element at `i` from the array table and return it, the following elements are shifted down,
+    /// equivalent to `table.remove` in lua. Returns nil if `i` is out of `1..=raw_len()`
+    pub fn remove(&self, i: usize) -> Result<ValRef<'l>> {
+        let len = self.raw_len_i64();
+        let i = i as lua_Integer;
+        if i < 1 || i > len {
+            return self.state.new_val(());
+        }
+        let removed = self.raw_geti(i)?;
+        for j in i..len {
+            self.raw_seti(j, self.raw_geti(j + 1)?)?;
+        }
+        self.raw_seti(len, ())?;
+        Ok(removed)
+    }
+
+    /// Push an element to end of the array part of a table, alias to `self.raw_seti(self.raw_len_i64() + 1, val)`
     #[inline(always)]
     pub fn push<V: ToLua>(&self, val: V) -> Result<()> {
-        self.raw_seti((self.raw_len() + 1) as i64, val)
+        self.raw_seti(self.raw_len_i64() + 1, val)
+    }
+
+    /// Append the elements to the array part, the length is computed only once,
+    /// which is much faster than calling [`Self::push`] repeatedly
+    pub fn extend<V: ToLua, I: IntoIterator<Item = V>>(&self, iter: I) -> Result<()> {
+        self.state.check_stack(1)?;
+        let mut i = self.raw_len_i64();
+        for val in iter {
+            i += 1;
+            self.state.push(val)?;
+            self.state.raw_seti(self.index, i);
+        }
+        Ok(())
     }
 
     /// Iterator to the table entries
@@ -736,7 +1116,45 @@ impl<'l> Table<'l> {
     }
 }
 
+/// Push the array part of a table as multiple values
+struct Unpack<'t, 'l>(&'t Table<'l>);
+
+impl ToLuaMulti for Unpack<'_, '_> {
+    fn value_count(&self) -> Option<usize> {
+        Some(self.0.raw_len())
+    }
+
+    fn push_multi(self, s: &State) -> Result<usize> {
+        let len = self.0.raw_len_i64();
+        for i in 1..=len {
+            s.raw_geti(self.0.index, i);
+        }
+        Ok(len as _)
+    }
+}
+
 impl<'a> Function<'a> {
+    /// Returns true if this function is implemented in C or rust, rather than defined in lua
+    pub fn is_native(&self) -> bool {
+        let mut ar: ffi::lua_Debug = unsafe { core::mem::zeroed() };
+        self.state.check_stack(1).expect("stack");
+        self.state.pushvalref(&self.0);
+        self.state.get_info(crate::cstr!(">S"), &mut ar);
+        ar.what().as_deref() == Some("C")
+    }
+
+    /// Returns true if this function is defined in lua
+    #[inline]
+    pub fn is_lua(&self) -> bool {
+        !self.is_native()
+    }
+
+    /// Call this function with the array part of `args` as the arguments, like `f(table.unpack(args))` in lua
+    #[inline]
+    pub fn apply<R: FromLuaMulti<'a>>(&self, args: &Table) -> Result<R> {
+        self.pcall(Unpack(args))
+    }
+
     #[inline(always)]
     pub fn get_upvalue(&self, i: Index) -> Result<Option<ValRef<'a>>> {
         self.get_upvalue_name(i).map(|x| x.map(|x| x.0))
@@ -757,6 +1175,32 @@ impl<'a> Function<'a> {
         Ok(())
     }
 
+    /// The table of the `_ENV` upvalue, `None` if this function has no `_ENV` like the C functions
+    pub fn environment(&self) -> Result<Option<Table<'a>>> {
+        Ok(self
+            .env_upvalue()?
+            .and_then(|(val, _)| Table::try_from(val).ok()))
+    }
+
+    /// Set the `_ENV` upvalue, raises error if this function has no `_ENV`
+    pub fn set_environment(&self, env: Table) -> Result<()> {
+        let (_, i) = self
+            .env_upvalue()?
+            .ok_or_else(|| Error::runtime("function has no _ENV upvalue"))?;
+        self.set_upvalue(i, env)
+    }
+
+    fn env_upvalue(&self) -> Result<Option<(ValRef<'a>, Index)>> {
+        for i in 1.. {
+            match self.get_upvalue_name(i)? {
+                Some((val, "_ENV")) => return Ok(Some((val, i))),
+                Some(_) => {}
+                None => break,
+            }
+        }
+        Ok(None)
+    }
+
     pub fn upvalues(&self) -> Result<Vec<ValRef<'a>>> {
         let mut result = Vec::new();
         for i in 1.. {
@@ -768,15 +1212,155 @@ impl<'a> Function<'a> {
         Ok(result)
     }
 
+    /// Wrap this function with a call counter, return the wrapper function and the counter,
+    /// the wrapper forwards all the arguments and results
+    pub fn instrument(&self) -> Result<(Function<'a>, CallCounter)> {
+        let counter = CallCounter::default();
+        self.state.check_stack(4)?;
+        self.state.push_userdatauv(counter.0.clone(), 0)?;
+        self.state
+            .push_binding(counted_call, __gc::<Arc<AtomicUsize>>, 1)?;
+        let wrapper: Function = self.state.top_val().try_into()?;
+        wrapper.set_upvalue(2, &self.0)?;
+        Ok((wrapper, counter))
+    }
+
     /// Dumps the function as a binary chunk.
     ///
     /// If `strip` is true, the binary representation may not include all debug information
     /// about the function, to save space.
     pub fn dump(&self, strip: bool) -> Vec<u8> {
         let mut data: Vec<u8> = Vec::new();
-        self.state.dump(|buf| data.extend_from_slice(buf), strip);
+        if self.state.check_stack(1).is_ok() {
+            self.state.pushvalref(&self.0);
+            self.state.dump(|buf| data.extend_from_slice(buf), strip);
+            self.state.pop(1);
+        }
         data
     }
+
+    /// Like [`Self::dump`], but write the binary chunk to `w` piece by piece
+    #[cfg(feature = "std")]
+    pub fn dump_to<W: std::io::Write>(&self, w: &mut W, strip: bool) -> Result<()> {
+        self.state.check_stack(1)?;
+        self.state.pushvalref(&self.0);
+        let mut result = Ok(());
+        self.state.dump(
+            |buf| {
+                if result.is_ok() {
+                    result = w.write_all(buf);
+                }
+            },
+            strip,
+        );
+        self.state.pop(1);
+        result.map_err(Error::from)
+    }
+}
+
+impl<'l> LuaThread<'l> {
+    fn thread(&self) -> State {
+        let l = self.state.to_thread(self.index).expect("thread");
+        unsafe { State::from_raw_state(l) }
+    }
+
+    /// Label this thread for debugging, the name is reported by [`State::thread_name`] and [`State::frames`]
+    pub fn set_name(&self, name: &str) -> Result<()> {
+        self.state.thread_names()?.raw_set(self.0.clone(), name)
+    }
+
+    /// Status of the coroutine, [`ThreadStatus::Ok`] for the coroutine which is not started or finished
+    #[inline]
+    pub fn status(&self) -> ThreadStatus {
+        self.thread().status()
+    }
+
+    /// Resume the coroutine with `args`, which are passed as the arguments of its function at the first time,
+    /// or returned from the `coroutine.yield` the coroutine suspended in
+    pub fn resume<A: ToLuaMulti, R: FromLuaMulti<'l>>(&self, args: A) -> Result<CoResult<R>> {
+        let (guard, status) = self.resume_raw(args)?;
+        let result_base = guard.top() + 1;
+        let result = self.state.to_multi_balance(guard, result_base)?;
+        Ok(if status == ThreadStatus::Yield {
+            CoResult::Yielded(result)
+        } else {
+            CoResult::Returned(result)
+        })
+    }
+
+    /// Resume the coroutine and move the results onto the stack above the returned guard
+    fn resume_raw<A: ToLuaMulti>(&self, args: A) -> Result<(StackGuard<'l>, ThreadStatus)> {
+        let s = self.state;
+        let guard = s.stack_guard();
+        let thread = self.thread();
+
+        s.check_stack(args.value_count().unwrap_or(10) as i32)?;
+        let nargs = s.push_multi(args)? as c_int;
+        thread.check_stack(nargs + 1)?;
+        s.xmove(thread.state, nargs);
+
+        let mut nres = 0;
+        match thread.resume(s.state, nargs, &mut nres) {
+            status @ (ThreadStatus::Ok | ThreadStatus::Yield) => {
+                s.check_stack(nres + 1)?;
+                thread.xmove(s.state, nres);
+                Ok((guard, status))
+            }
+            err => Err(thread.status_to_error(err).unwrap_err()),
+        }
+    }
+
+    /// Iterate the values yielded by the coroutine, which is resumed without arguments each time,
+    /// the iteration stops after the coroutine returned, or an error is returned
+    #[allow(clippy::should_implement_trait)]
+    pub fn into_iter<R: FromLuaMulti<'l> + 'static>(self) -> impl Iterator<Item = Result<R>> + 'l {
+        let mut done = false;
+        core::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            // the values returned at the end are dropped
+            let result = self.resume_raw(()).and_then(|(guard, status)| {
+                let result_base = guard.top() + 1;
+                (status == ThreadStatus::Yield)
+                    .then(|| self.state.to_multi_balance(guard, result_base))
+                    .transpose()
+            });
+            done = !matches!(result, Ok(Some(_)));
+            result.transpose()
+        })
+    }
+}
+
+/// Invocation count of an instrumented function, see [`Function::instrument`]
+#[derive(Debug, Clone, Default)]
+pub struct CallCounter(Arc<AtomicUsize>);
+
+impl CallCounter {
+    /// How many times the instrumented function has been called
+    #[inline(always)]
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+unsafe extern "C-unwind" fn counted_call(l: *mut ffi::lua_State) -> c_int {
+    let counter = ffi::lua_touserdata(l, ffi::lua_upvalueindex(1)) as *const Arc<AtomicUsize>;
+    (*counter).fetch_add(1, Ordering::Relaxed);
+
+    unsafe extern "C-unwind" fn forward_results(
+        l: *mut ffi::lua_State,
+        _status: c_int,
+        _ctx: ffi::lua_KContext,
+    ) -> c_int {
+        ffi::lua_gettop(l)
+    }
+
+    let nargs = ffi::lua_gettop(l);
+    ffi::lua_pushvalue(l, ffi::lua_upvalueindex(2));
+    ffi::lua_rotate(l, 1, 1);
+    ffi::lua_callk(l, nargs, ffi::LUA_MULTRET, 0, Some(forward_results));
+    ffi::lua_gettop(l)
 }
 
 impl<'a> AsRef<Table<'a>> for Table<'a> {
@@ -790,6 +1374,32 @@ impl<'a> LuaString<'a> {
     pub fn to_string_lossy(&self) -> Cow<str> {
         self.state.to_string_lossy(self.index).unwrap_or_default()
     }
+
+    /// Convert the string to number by the lua rules, like `tonumber` in lua,
+    /// returns [`Value::Integer`] or [`Value::Number`], or `None` if it's not a numeral
+    pub fn as_number(&self) -> Option<Value<'a>> {
+        self.state.check_stack(1).ok()?;
+        let l = self.state.state;
+        let mut len = 0;
+        let size = unsafe {
+            let p = ffi::lua_tolstring(l, self.index, &mut len);
+            if p.is_null() {
+                return None;
+            }
+            ffi::lua_stringtonumber(l, p)
+        };
+        if size == 0 {
+            return None;
+        }
+        let result = if self.state.is_integer(-1) {
+            Value::Integer(self.state.to_integer(-1))
+        } else {
+            Value::Number(self.state.to_number(-1))
+        };
+        self.state.pop(1);
+        // the string contains '\0'
+        (size == len + 1).then_some(result)
+    }
 }
 
 impl<'a> LuaUserData<'a> {
@@ -846,6 +1456,38 @@ impl<'a> LuaUserData<'a> {
         }
     }
 
+    /// Replace the contained value in place and return the old one, the identity and uservalues are kept.
+    /// It fails if the value is borrowed currently, such as a method of this userdata is running,
+    /// only the userdata stored in `RefCell` or `RwLock` can be checked, see [`Self::replace_unchecked`] for the others
+    pub fn replace<U: UserData>(&self, new: U) -> Result<U> {
+        self.userdata_ref::<U>()
+            .ok_or("userdata not match")
+            .lua_result()?
+            .try_replace(new)
+            .map_err(|_| Error::runtime("userdata is borrowed or can't be replaced"))
+    }
+
+    /// Like [`Self::replace`], but without checking the borrows
+    ///
+    /// # Safety
+    ///
+    /// There must be no reference to the contained value alive, including the `&U` or the guard
+    /// held by a running method of this userdata, otherwise it dangles after the old value is dropped
+    pub unsafe fn replace_unchecked<U: UserData>(&self, new: U) -> Result<U::Trans> {
+        if self.raw_len() != core::mem::size_of::<U::Trans>() {
+            return Err(Error::runtime("userdata size not match"));
+        }
+        let p = self
+            .userdata_ref_mut::<U>()
+            .ok_or("userdata not match")
+            .lua_result()?;
+        let old = core::mem::replace(p, <U::Trans as UserDataTrans<U>>::FROM_INNER(new));
+        if let Some(init_userdata) = <U::Trans as UserDataTrans<U>>::INIT_USERDATA {
+            init_userdata(self.state, p);
+        }
+        Ok(old)
+    }
+
     pub fn userdata_pointer(&self) -> *mut c_void {
         self.state.to_userdata(self.index)
     }
@@ -978,3 +1620,233 @@ impl PartialEq for ValRef<'_> {
             .unwrap_or_default()
     }
 }
+
+impl ValRef<'_> {
+    /// Compare with `other` by `lua_compare`, which respects the metamethods,
+    /// the errors raised by the metamethods are returned
+    pub fn compare(&self, other: &Self, op: Comparison) -> Result<bool> {
+        let callback = match op {
+            Comparison::Eq => protect_compare!(ffi::LUA_OPEQ),
+            Comparison::Lt => protect_compare!(ffi::LUA_OPLT),
+            Comparison::Le => protect_compare!(ffi::LUA_OPLE),
+        };
+        self.state.protect_call((self, other), callback)
+    }
+
+    /// Same as `self < other` in lua
+    #[inline]
+    pub fn lt(&self, other: &Self) -> Result<bool> {
+        self.compare(other, Comparison::Lt)
+    }
+
+    /// Same as `self <= other` in lua
+    #[inline]
+    pub fn le(&self, other: &Self) -> Result<bool> {
+        self.compare(other, Comparison::Le)
+    }
+}
+
+/// Fully owned representation of a lua value, detached from the lua state
+#[derive(Debug, Clone, PartialEq)]
+pub enum OwnedValue {
+    Nil,
+    Bool(bool),
+    Integer(lua_Integer),
+    Number(lua_Number),
+    String(String),
+    /// String which is not valid utf8
+    Bytes(Vec<u8>),
+    Table {
+        /// Values of the sequence part `1..=#t`
+        array: Vec<OwnedValue>,
+        /// The other entries
+        hash: BTreeMap<OwnedKey, OwnedValue>,
+    },
+    /// Placeholder for the values can not be owned, such as function, userdata and thread
+    Opaque(Type),
+}
+
+/// Table key of [`OwnedValue::Table`]
+#[derive(Debug, Clone)]
+pub enum OwnedKey {
+    Bool(bool),
+    Integer(lua_Integer),
+    Number(lua_Number),
+    String(String),
+    Bytes(Vec<u8>),
+}
+
+impl OwnedKey {
+    fn rank(&self) -> u8 {
+        match self {
+            Self::Bool(_) => 0,
+            Self::Integer(_) => 1,
+            Self::Number(_) => 2,
+            Self::String(_) => 3,
+            Self::Bytes(_) => 4,
+        }
+    }
+}
+
+impl Ord for OwnedKey {
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+        match (self, other) {
+            (Self::Bool(a), Self::Bool(b)) => a.cmp(b),
+            (Self::Integer(a), Self::Integer(b)) => a.cmp(b),
+            (Self::Number(a), Self::Number(b)) => a.total_cmp(b),
+            (Self::String(a), Self::String(b)) => a.cmp(b),
+            (Self::Bytes(a), Self::Bytes(b)) => a.cmp(b),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
+
+impl PartialOrd for OwnedKey {
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for OwnedKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for OwnedKey {}
+
+/// Stable sort with a fallible comparison, unlike `slice::sort_by`,
+/// an inconsistent comparison from lua doesn't cause panic
+fn merge_sort<T: Copy>(v: &mut [T], less: &mut impl FnMut(&T, &T) -> Result<bool>) -> Result<()> {
+    if v.len() <= 1 {
+        return Ok(());
+    }
+    let mid = v.len() / 2;
+    merge_sort(&mut v[..mid], less)?;
+    merge_sort(&mut v[mid..], less)?;
+    // the halves are already in order
+    if !less(&v[mid], &v[mid - 1])? {
+        return Ok(());
+    }
+
+    let left = v[..mid].to_vec();
+    let (mut i, mut j, mut k) = (0, mid, 0);
+    while i < left.len() && j < v.len() {
+        if less(&v[j], &left[i])? {
+            v[k] = v[j];
+            j += 1;
+        } else {
+            v[k] = left[i];
+            i += 1;
+        }
+        k += 1;
+    }
+    v[k..k + left.len() - i].copy_from_slice(&left[i..]);
+    Ok(())
+}
+
+fn owned_string(bytes: &[u8]) -> core::result::Result<String, Vec<u8>> {
+    core::str::from_utf8(bytes)
+        .map(Into::into)
+        .map_err(|_| bytes.to_vec())
+}
+
+impl<'a> ValRef<'a> {
+    /// Convert to fully owned rust data recursively, recursive tables are treated as error
+    pub fn to_owned_value(&self) -> Result<OwnedValue> {
+        self.owned_value(&mut Vec::new())
+    }
+
+    fn owned_value(&self, visited: &mut Vec<*const c_void>) -> Result<OwnedValue> {
+        Ok(match self.type_of() {
+            Type::None | Type::Nil => OwnedValue::Nil,
+            Type::Boolean => OwnedValue::Bool(self.to_bool()),
+            Type::Number if self.is_integer() => OwnedValue::Integer(self.to_integer()),
+            Type::Number => OwnedValue::Number(self.to_number()),
+            Type::String => match owned_string(self.to_bytes().unwrap_or_default()) {
+                Ok(s) => OwnedValue::String(s),
+                Err(b) => OwnedValue::Bytes(b),
+            },
+            Type::Table => {
+                let ptr = self.to_pointer();
+                if visited.contains(&ptr) {
+                    return Err(Error::convert("recursive table"));
+                }
+                visited.push(ptr);
+
+                let t = self.as_table().expect("table");
+                let len = t.raw_len();
+                let mut array = Vec::with_capacity(len);
+                for i in 1..=len {
+                    array.push(t.raw_geti(i as lua_Integer)?.owned_value(visited)?);
+                }
+                let mut hash = BTreeMap::new();
+                for (k, v) in t.iter()? {
+                    if k.is_integer() && (1..=len as lua_Integer).contains(&k.to_integer()) {
+                        continue;
+                    }
+                    hash.insert(k.owned_key()?, v.owned_value(visited)?);
+                }
+
+                visited.pop();
+                OwnedValue::Table { array, hash }
+            }
+            ty => OwnedValue::Opaque(ty),
+        })
+    }
+
+    fn owned_key(&self) -> Result<OwnedKey> {
+        Ok(match self.type_of() {
+            Type::Boolean => OwnedKey::Bool(self.to_bool()),
+            Type::Number if self.is_integer() => OwnedKey::Integer(self.to_integer()),
+            Type::Number => OwnedKey::Number(self.to_number()),
+            Type::String => match owned_string(self.to_bytes().unwrap_or_default()) {
+                Ok(s) => OwnedKey::String(s),
+                Err(b) => OwnedKey::Bytes(b),
+            },
+            ty => return Err(Error::convert(format!("unsupported table key type: {ty}"))),
+        })
+    }
+}
+
+impl ToLua for &OwnedKey {
+    fn to_lua<'a>(self, lua: &'a State) -> Result<ValRef<'a>> {
+        match self {
+            OwnedKey::Bool(b) => lua.new_val(*b),
+            OwnedKey::Integer(i) => lua.new_val(*i),
+            OwnedKey::Number(n) => lua.new_val(*n),
+            OwnedKey::String(s) => lua.new_val(s.as_str()),
+            OwnedKey::Bytes(b) => lua.new_val(b.as_slice()),
+        }
+    }
+}
+
+impl ToLua for &OwnedValue {
+    fn to_lua<'a>(self, lua: &'a State) -> Result<ValRef<'a>> {
+        match self {
+            OwnedValue::Nil | OwnedValue::Opaque(_) => lua.new_val(()),
+            OwnedValue::Bool(b) => lua.new_val(*b),
+            OwnedValue::Integer(i) => lua.new_val(*i),
+            OwnedValue::Number(n) => lua.new_val(*n),
+            OwnedValue::String(s) => lua.new_val(s.as_str()),
+            OwnedValue::Bytes(b) => lua.new_val(b.as_slice()),
+            OwnedValue::Table { array, hash } => {
+                let t = lua.new_table_with_size(array.len() as _, hash.len() as _)?;
+                for (i, v) in array.iter().enumerate() {
+                    t.raw_seti(i as lua_Integer + 1, v)?;
+                }
+                for (k, v) in hash {
+                    t.raw_set(k, v)?;
+                }
+                Ok(t.0)
+            }
+        }
+    }
+}
+
+impl ToLua for OwnedValue {
+    #[inline(always)]
+    fn to_lua<'a>(self, lua: &'a State) -> Result<ValRef<'a>> {
+        (&self).to_lua(lua)
+    }
+}
@@ -105,6 +105,28 @@ async fn sync() {
     assert_eq!(ret.cast::<i32>().unwrap(), 3);
 }
 
+#[tokio::test]
+async fn userdata_async_function() {
+    struct Counter(i32);
+
+    impl UserData for Counter {
+        fn methods(methods: UserdataRegistry<Self>) -> LuaResult<()> {
+            methods.add_async("create", |_, n: i32| async move {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                Counter(n * 2)
+            })?;
+            methods.add_field_get("value", |_, this| this.0)?;
+            Ok(())
+        }
+    }
+
+    let lua = Lua::with_open_libs();
+    lua.global().set("counter", Counter(0)).unwrap();
+    let foo = lua.load("return counter.create(21):value()", None).unwrap();
+    let ret = foo.call_async::<_, i32>(()).await.unwrap();
+    assert_eq!(ret, 42);
+}
+
 #[tokio::test]
 async fn async_stack_balance() {
     let lua = Lua::new();
@@ -216,3 +238,180 @@ async fn memory_leak() {
 
     drop(lua);
 }
+
+#[test]
+fn pcall_on_yield() {
+    let lua = Lua::with_open_libs();
+    let fun = lua
+        .load(
+            r#"
+            local a = ...
+            local b = coroutine.yield(a + 1)
+            local c, d = coroutine.yield(a + b, 'second')
+            return a + b + c + d
+            "#,
+            None,
+        )
+        .unwrap();
+
+    let mut yielded = vec![];
+    let result = lua
+        .pcall_on_yield::<i32>(fun.clone(), 1, |s, vals| {
+            yielded.push(vals.0.len());
+            let LuaValue::Integer(n) = vals.0[0] else {
+                unreachable!()
+            };
+            Ok(MultiRet(vec![s.new_value(n * 10)?, s.new_value(n)?]))
+        })
+        .unwrap();
+    assert_eq!(yielded, [1, 2]);
+    // a = 1, b = 20, c = 210, d = 21
+    assert_eq!(result, 252);
+
+    let err = lua
+        .pcall_on_yield::<i32>(fun, 1, |_, _| Err(LuaError::runtime("stop")))
+        .unwrap_err();
+    assert!(format!("{err:?}").contains("stop"));
+
+    // the yielded values are passed back directly, also yielded from rust with other slots in its frame
+    lua.global()
+        .set(
+            "ryield",
+            lua.new_function(|lua, (t, s): (LuaTable, LuaString)| -> () { lua.yield_with((s, t)) })
+                .unwrap(),
+        )
+        .unwrap();
+    let echo = lua
+        .load(
+            r#"
+            local t, s = coroutine.yield('a', {1})
+            local t2, s2 = ryield(t, s .. 'b')
+            return s2, t2[1]
+            "#,
+            None,
+        )
+        .unwrap();
+    let result = lua
+        .pcall_on_yield::<(String, i32)>(echo, (), |_, vals| {
+            Ok(MultiRet(vals.0.into_iter().rev().collect()))
+        })
+        .unwrap();
+    assert_eq!(result, ("ab".into(), 1));
+}
+
+#[test]
+fn thread_name() {
+    let lua = Lua::with_open_libs();
+    lua.global()
+        .set_function("where_am_i", |s, ()| {
+            s.frames(1, false)
+                .first()
+                .and_then(|frame| frame.thread.clone())
+        })
+        .unwrap();
+    let new_thread = || -> LuaThread {
+        lua.exec(
+            "return coroutine.create(function() coroutine.yield(where_am_i()) end)",
+            None,
+        )
+        .unwrap()
+    };
+
+    let (a, b) = (new_thread(), new_thread());
+    a.set_name("worker-a").unwrap();
+    b.set_name("worker-b").unwrap();
+    assert_eq!(
+        b.resume::<_, String>(()).unwrap(),
+        CoResult::Yielded("worker-b".into())
+    );
+    assert_eq!(
+        a.resume::<_, String>(()).unwrap(),
+        CoResult::Yielded("worker-a".into())
+    );
+    assert_eq!(lua.thread_name(), None);
+}
+
+#[test]
+fn thread_resume() {
+    let lua = Lua::with_open_libs();
+    let co: LuaThread = lua
+        .load(
+            r#"
+            return coroutine.create(function(n)
+                for i = 1, n do
+                    local reply = coroutine.yield(i, i * i)
+                    assert(reply == i)
+                end
+                return 'done'
+            end)
+            "#,
+            None,
+        )
+        .unwrap()
+        .pcall::<_, ValRef>(())
+        .unwrap()
+        .try_into()
+        .unwrap();
+
+    assert_eq!(co.status(), ThreadStatus::Ok);
+    assert_eq!(
+        co.resume::<_, (i32, i32)>(3).unwrap(),
+        CoResult::Yielded((1, 1))
+    );
+    assert_eq!(co.status(), ThreadStatus::Yield);
+    assert_eq!(
+        co.resume::<_, (i32, i32)>(1).unwrap(),
+        CoResult::Yielded((2, 4))
+    );
+    assert_eq!(
+        co.resume::<_, (i32, i32)>(2).unwrap(),
+        CoResult::Yielded((3, 9))
+    );
+    assert_eq!(
+        co.resume::<_, String>(3).unwrap(),
+        CoResult::Returned("done".to_string())
+    );
+    assert!(co.resume::<_, ()>(()).is_err());
+
+    let co: LuaThread = lua
+        .load(
+            "return coroutine.create(function() coroutine.yield(); error('boom') end)",
+            None,
+        )
+        .unwrap()
+        .pcall::<_, ValRef>(())
+        .unwrap()
+        .try_into()
+        .unwrap();
+    assert_eq!(co.resume::<_, ()>(()).unwrap(), CoResult::Yielded(()));
+    let err = co.resume::<_, ()>(()).unwrap_err();
+    assert!(format!("{err:?}").contains("boom"));
+}
+
+#[test]
+fn thread_iterator() {
+    let lua = Lua::with_open_libs();
+    let create = |code: &str| -> LuaThread {
+        lua.load(code, None)
+            .unwrap()
+            .pcall::<_, ValRef>(())
+            .unwrap()
+            .try_into()
+            .unwrap()
+    };
+
+    let co =
+        create("return coroutine.create(function() for i = 1, 5 do coroutine.yield(i) end end)");
+    let evens = co
+        .into_iter::<i32>()
+        .map(Result::unwrap)
+        .filter(|i| i % 2 == 0)
+        .collect::<Vec<_>>();
+    assert_eq!(evens, [2, 4]);
+
+    let co = create("return coroutine.create(function() coroutine.yield(1); error('boom') end)");
+    let mut iter = co.into_iter::<i32>();
+    assert_eq!(iter.next().unwrap().unwrap(), 1);
+    assert!(iter.next().unwrap().is_err());
+    assert!(iter.next().is_none());
+}
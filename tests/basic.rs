@@ -1,4 +1,4 @@
-use std::cell::{RefCell, RefMut};
+use std::cell::{Cell, RefCell, RefMut};
 use std::rc::Rc;
 
 use ::serde::{Deserialize, Serialize};
@@ -105,6 +105,227 @@ fn userdata() {
     assert_eq!(ud.get_iuservalue(3).unwrap().to_integer(), 2333);
 }
 
+struct Vec3(i32, i32, i32);
+
+impl UserData for Vec3 {
+    const TYPE_NAME: &'static str = "Vec3";
+    const TO_STRING: Option<fn(&Self) -> String> =
+        Some(|v| format!("Vec3({},{},{})", v.0, v.1, v.2));
+}
+
+#[test]
+fn userdata_tostring() {
+    let s = Lua::with_open_libs();
+    s.global().set("v", Vec3(1, 2, 3)).unwrap();
+    s.global().set("t", Test { a: 0 }).unwrap();
+    s.do_string("assert(tostring(v) == 'Vec3(1,2,3)')", None)
+        .unwrap();
+    s.do_string("print(v)", None).unwrap();
+    // the default format of lua uses `__name`
+    s.do_string(
+        "assert(tostring(t):find(getmetatable(t).__name, 1, true) == 1)",
+        None,
+    )
+    .unwrap();
+}
+
+struct Counter(i32);
+
+impl UserData for Counter {
+    type Trans = RefCell<Self>;
+
+    const TO_STRING: Option<fn(&Self) -> String> = Some(|c| format!("Counter({})", c.0));
+
+    fn methods(mt: UserdataRegistry<Self>) -> LuaResult<()> {
+        mt.add_method_mut("with", |_, this, f: LuaFunction| {
+            this.0 += 1;
+            f.pcall_void(())
+        })?;
+        Ok(())
+    }
+}
+
+#[test]
+fn userdata_tostring_borrowed() {
+    let s = Lua::with_open_libs();
+    s.global().set("c", Counter(0)).unwrap();
+    s.do_string(
+        r#"
+        c:with(function()
+            local ok, err = pcall(tostring, c)
+            assert(not ok and err:find('mutably borrowed', 1, true))
+        end)
+        assert(tostring(c) == 'Counter(1)')
+        "#,
+        None,
+    )
+    .unwrap();
+}
+
+#[derive(Clone, Copy)]
+struct BigInt(i64);
+
+impl UserData for BigInt {
+    fn getter(fields: UserdataRegistry<Self>) -> LuaResult<()> {
+        fields.add_field_get("value", |_, this| this.0)?;
+        Ok(())
+    }
+
+    fn metatable(mt: UserdataRegistry<Self>) -> LuaResult<()> {
+        mt.add_meta_method("__add", |_, this, other: &Self| BigInt(this.0 + other.0))?;
+        mt.add_meta_method("__sub", |_, this, other: &Self| BigInt(this.0 - other.0))?;
+        mt.add_meta_method("__eq", |_, this, other: &Self| this.0 == other.0)?;
+        mt.add_meta_method("__lt", |_, this, other: &Self| this.0 < other.0)?;
+        mt.add_meta_method("__le", |_, this, other: &Self| this.0 <= other.0)?;
+        Ok(())
+    }
+}
+
+#[test]
+fn userdata_metamethods() {
+    let s = Lua::with_open_libs();
+    s.global().set("a", BigInt(2)).unwrap();
+    s.global().set("b", BigInt(5)).unwrap();
+    s.do_string("assert((a + b).value == 7 and (a - b).value == -3)", None)
+        .unwrap();
+    s.do_string("assert(a < b and a <= b and not (b < a) and b >= a)", None)
+        .unwrap();
+    s.do_string("assert(a + b == b + a and a ~= b)", None)
+        .unwrap();
+    s.do_string("return a + 1", None).unwrap_err();
+
+    let mt = s.new_table().unwrap();
+    let registry = UserdataRegistry::<BigInt>::new(&mt);
+    assert!(registry
+        .add_meta_method("__plus", |_, this, _: ()| this.0)
+        .is_err());
+}
+
+struct Matcher(String);
+
+impl UserData for Matcher {
+    fn methods(methods: UserdataRegistry<Self>) -> LuaResult<()> {
+        methods.add_method("pattern", |_, this, ()| this.0.clone())?;
+        Ok(())
+    }
+
+    fn metatable(mt: UserdataRegistry<Self>) -> LuaResult<()> {
+        mt.add_call(|_, this, (input, prefix): (String, Option<String>)| {
+            format!("{}{}", prefix.unwrap_or_default(), input.contains(&this.0))
+        })?;
+        Ok(())
+    }
+}
+
+#[test]
+fn userdata_call() {
+    let s = Lua::with_open_libs();
+    s.global().set("matcher", Matcher("ab".into())).unwrap();
+    s.do_string("assert(matcher('xaby') == 'true')", None)
+        .unwrap();
+    s.do_string("assert(matcher('xy', '>') == '>false')", None)
+        .unwrap();
+    s.do_string("assert(matcher:pattern() == 'ab')", None)
+        .unwrap();
+}
+
+struct OrderedMap(std::collections::BTreeMap<String, i32>);
+
+impl UserData for OrderedMap {
+    const INDEX_USERVALUE: bool = true;
+
+    fn metatable(mt: UserdataRegistry<Self>) -> LuaResult<()> {
+        mt.add_pairs(|_, this| StaticIter::from(this.0.clone().into_iter()))?;
+        Ok(())
+    }
+}
+
+#[test]
+fn userdata_pairs() {
+    let s = Lua::with_open_libs();
+    let map = [("b", 2), ("a", 1), ("c", 3)]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect();
+    s.global().set("map", OrderedMap(map)).unwrap();
+    let pairs: String = s
+        .exec(
+            "map.extra = 0
+            local result = {}
+            for k, v in pairs(map) do result[#result + 1] = k .. '=' .. v end
+            return table.concat(result, ',')",
+            None,
+        )
+        .unwrap();
+    assert_eq!(pairs, "a=1,b=2,c=3");
+}
+
+#[test]
+fn userdata_replace() {
+    let lua = Lua::with_open_libs();
+    let ud = lua.new_userdata(Test { a: 1 }).unwrap();
+    ud.set_uservalue(lua.new_table().unwrap()).unwrap();
+    lua.global().set("ud", ud.clone()).unwrap();
+    lua.do_string("ud.tag = 'pooled'", None).unwrap();
+    let ptr = ud.to_pointer();
+
+    let old = ud.replace(Test { a: 2 }).unwrap();
+    assert_eq!(old.a, 1);
+    assert_eq!(ud.to_pointer(), ptr);
+    lua.do_string("assert(ud.a == 2 and ud.tag == 'pooled')", None)
+        .unwrap();
+    assert!(ud.replace(Vec3(0, 0, 0)).is_err());
+
+    // the userdata not in RefCell can't be checked, only be replaced unsafely
+    let v = lua.new_userdata(Vec3(1, 2, 3)).unwrap();
+    assert!(v.replace(Vec3(0, 0, 0)).is_err());
+    let old = unsafe { v.replace_unchecked(Vec3(4, 5, 6)) }.unwrap();
+    assert_eq!((old.0, old.1, old.2), (1, 2, 3));
+}
+
+#[test]
+fn userdata_replace_borrowed() {
+    struct Counter(i32);
+
+    impl UserData for Counter {
+        type Trans = RefCell<Self>;
+
+        fn methods(mt: UserdataRegistry<Self>) -> LuaResult<()> {
+            mt.add_method("with", |_, this, f: LuaFunction| {
+                f.pcall_void(())?;
+                LuaResult::Ok(this.0)
+            })?;
+            Ok(())
+        }
+
+        fn getter(fields: UserdataRegistry<Self>) -> LuaResult<()> {
+            fields.add_field_get("n", |_, this| this.0)?;
+            Ok(())
+        }
+    }
+
+    let lua = Lua::with_open_libs();
+    let g = lua.global();
+    g.set("counter", lua.new_userdata(Counter(1)).unwrap())
+        .unwrap();
+    g.set_closure("swap", |ud: LuaUserData, n: i32| {
+        ud.replace(Counter(n)).map(|old| old.0)
+    })
+    .unwrap();
+    lua.do_string(
+        r#"
+        -- the method is running and holding the borrow
+        assert(counter:with(function()
+            local ok, err = pcall(swap, counter, 2)
+            assert(not ok and tostring(err):find 'borrowed', err)
+        end) == 1)
+        assert(swap(counter, 3) == 1 and counter.n == 3)
+        "#,
+        None,
+    )
+    .unwrap();
+}
+
 #[test]
 fn iter() {
     let lua = Lua::with_open_libs();
@@ -143,6 +364,20 @@ fn dump() {
     );
 }
 
+#[test]
+fn dump_to_writer() {
+    let lua = Lua::with_open_libs();
+    let fun = lua.load("return 1134 + 2243", None).unwrap();
+    let _other = lua.new_val("top").unwrap();
+
+    let mut buf = Vec::new();
+    fun.dump_to(&mut buf, true).unwrap();
+    assert_eq!(buf, fun.dump(true));
+
+    let fun2 = lua.load(buf, None).unwrap();
+    assert_eq!(fun2.pcall::<_, i32>(()).unwrap(), 3377);
+}
+
 #[test]
 fn arguments() -> LuaResult<()> {
     let s = Lua::with_open_libs();
@@ -286,6 +521,55 @@ fn stack_balance() {
     }
 }
 
+#[test]
+fn balance_guard() {
+    use ezlua::luaapi::UnsafeLuaApi;
+
+    let lua = Lua::new();
+    let top = lua.stack_top();
+    {
+        let guard = lua.balance_guard(1);
+        assert_eq!(guard.top(), top);
+        lua.push_integer(1);
+    }
+    assert_eq!(lua.stack_top(), top + 1);
+    lua.pop(1);
+
+    // the leaked values are cleaned up, and reported in debug builds
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let _guard = lua.balance_guard(0);
+        lua.push_integer(1);
+        lua.push_integer(2);
+    }));
+    assert_eq!(result.is_err(), cfg!(debug_assertions));
+    assert_eq!(lua.stack_top(), top);
+}
+
+#[test]
+fn extra_pointer() {
+    struct Host {
+        calls: usize,
+    }
+
+    let lua = Lua::with_open_libs();
+    assert!(lua.extra_ptr::<Host>().is_null());
+    let mut host = Host { calls: 0 };
+    lua.set_extra_ptr(&mut host as *mut Host);
+    lua.global()
+        .set_function("hot", |s, ()| {
+            let host = unsafe { &mut *s.extra_ptr::<Host>() };
+            host.calls += 1;
+            host.calls
+        })
+        .unwrap();
+    lua.do_string(
+        "hot() coroutine.wrap(function() assert(hot() == 2) end)()",
+        None,
+    )
+    .unwrap();
+    assert_eq!(host.calls, 2);
+}
+
 #[test]
 fn gc() {
     let lua = Lua::with_open_libs();
@@ -455,6 +739,55 @@ fn error_balance() {
     }
 }
 
+#[test]
+fn value_accessors() {
+    let lua = Lua::with_open_libs();
+    let val: LuaValue = lua.exec("return {1, 2, 3}", None).unwrap();
+    assert_eq!(val.as_table().unwrap().raw_len(), 3);
+    assert!(val.as_function().is_none());
+    let table = val.into_table().unwrap();
+    assert_eq!(table.raw_geti(2).unwrap().to_integer(), 2);
+
+    let val: LuaValue = lua.exec("return 'abc'", None).unwrap();
+    assert!(val.as_table().is_none());
+    assert_eq!(val.into_string().unwrap().to_str().unwrap(), "abc");
+    let val: LuaValue = lua.exec("return print", None).unwrap();
+    assert!(val.into_function().is_some());
+}
+
+#[test]
+fn globals_summary() {
+    let lua = Lua::with_open_libs();
+    assert!(lua.globals_summary().unwrap().is_empty());
+    lua.do_string("counter = 1; function greet() end", None)
+        .unwrap();
+    assert_eq!(
+        lua.globals_summary().unwrap(),
+        [
+            ("counter".to_string(), "integer"),
+            ("greet".to_string(), "function")
+        ]
+    );
+}
+
+#[test]
+fn spread_values() {
+    let lua = Lua::with_open_libs();
+    lua.global()
+        .set_function("spread", |_, ()| Spread(vec![1, 2, 3]))
+        .unwrap();
+    lua.global()
+        .set_function("array", |_, ()| vec![1, 2, 3])
+        .unwrap();
+    lua.do_string(
+        "local a, b, c = spread() assert(a == 1 and b == 2 and c == 3)
+        assert(select('#', spread()) == 3)
+        assert(type(array()) == 'table' and select('#', array()) == 1)",
+        None,
+    )
+    .unwrap();
+}
+
 #[test]
 fn convert() {
     let s = Lua::with_open_libs();
@@ -598,6 +931,28 @@ fn arith() {
     assert_eq!((-a.clone()).unwrap(), lua.new_val(-3).unwrap());
 }
 
+#[test]
+fn exhaust_stack() {
+    let lua = Lua::with_open_libs();
+    let err = lua.check_stack(10_000_000).unwrap_err();
+    assert!(err.is_stack_overflow());
+    assert!(matches!(
+        err,
+        LuaError::StackOverflow {
+            requested: 10_000_000
+        }
+    ));
+
+    let func = lua.load("return select('#', ...)", None).unwrap();
+    let top = lua.stack_top();
+    let err = func
+        .pcall::<_, usize>(Spread(vec![0; 2_000_000]))
+        .unwrap_err();
+    assert!(err.is_stack_overflow(), "{err:?}");
+    assert_eq!(lua.stack_top(), top);
+    lua.check_stack(10).unwrap();
+}
+
 #[test]
 fn stack_overflow() {
     let lua = Lua::with_open_libs();
@@ -630,3 +985,2055 @@ fn stack_overflow() {
         t.join().unwrap()
     }
 }
+
+#[test]
+fn memory_limit() {
+    let lua = Lua::with_memory_limit(1024 * 1024).unwrap();
+    assert_eq!(lua.memory_limit(), Some(1024 * 1024));
+    assert_eq!(Lua::new().memory_limit(), None);
+
+    let top = lua.stack_top();
+    let err = lua
+        .do_string(
+            "local t = {} for i = 1, 1000000 do t[i] = 'item' .. i end",
+            None,
+        )
+        .unwrap_err();
+    assert!(matches!(err, LuaError::Memory(_)), "{err:?}");
+    assert_eq!(lua.stack_top(), top);
+
+    lua.gc_collect().unwrap();
+    assert!(lua.used_memory() <= 1024 * 1024);
+    lua.do_string("local t = {} for i = 1, 100 do t[i] = i end", None)
+        .unwrap();
+}
+
+#[test]
+fn custom_allocator() {
+    use core::ffi::c_void;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[derive(Default)]
+    struct Counter {
+        allocs: AtomicUsize,
+        frees: AtomicUsize,
+    }
+
+    struct CountingAllocator(Arc<Counter>);
+
+    impl LuaAllocator for CountingAllocator {
+        unsafe fn alloc(&self, size: usize) -> *mut c_void {
+            self.0.allocs.fetch_add(1, Ordering::Relaxed);
+            SystemAllocator.alloc(size)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut c_void, old: usize, new: usize) -> *mut c_void {
+            SystemAllocator.realloc(ptr, old, new)
+        }
+
+        unsafe fn free(&self, ptr: *mut c_void, size: usize) {
+            self.0.frees.fetch_add(1, Ordering::Relaxed);
+            SystemAllocator.free(ptr, size)
+        }
+    }
+
+    let counter = Arc::new(Counter::default());
+    let lua = Lua::with_allocator(CountingAllocator(counter.clone())).unwrap();
+    lua.do_string(
+        "local t = {} for i = 1, 100 do t[i] = 'item' .. i end",
+        None,
+    )
+    .unwrap();
+    assert!(counter.allocs.load(Ordering::Relaxed) > 100);
+
+    drop(lua);
+    assert_eq!(
+        counter.allocs.load(Ordering::Relaxed),
+        counter.frees.load(Ordering::Relaxed)
+    );
+    assert_eq!(Arc::strong_count(&counter), 1);
+}
+
+#[test]
+fn bitflags() {
+    use ezlua::luaapi::HookMask;
+
+    let lua = Lua::with_open_libs();
+    let bits = (HookMask::MASKCALL | HookMask::MASKLINE).bits();
+    let val = lua
+        .new_val(BitFlags(HookMask::from_bits_retain(bits)))
+        .unwrap();
+    assert_eq!(val.to_integer(), bits as i64);
+    assert_eq!(lua.from_flags::<HookMask>(&val).unwrap().bits(), bits);
+
+    let val = lua.new_val(0x100).unwrap();
+    assert!(lua.from_flags::<HookMask>(&val).is_err());
+}
+
+#[test]
+fn override_global() {
+    let lua = Lua::with_open_libs();
+    let os = lua.global().get("os").unwrap();
+    let time = os.get("time").unwrap();
+    {
+        let fixed = lua.new_table().unwrap();
+        fixed
+            .set("time", lua.new_closure(|| 1234).unwrap())
+            .unwrap();
+        let _guard = lua.override_global("os", fixed).unwrap();
+        assert_eq!(
+            lua.load("return os.time()", None)
+                .unwrap()
+                .pcall::<_, i64>(())
+                .unwrap(),
+            1234
+        );
+    }
+    let os = lua.global().get("os").unwrap();
+    assert!(os.get("time").unwrap().raw_equal(&time));
+}
+
+#[test]
+fn table_part_sizes() {
+    let lua = Lua::with_open_libs();
+    let t = lua
+        .load("return {1, 2, 3, a = 1, b = 2, [10] = 10, [0] = 0}", None)
+        .unwrap()
+        .pcall::<_, LuaTable>(())
+        .unwrap();
+    assert_eq!(t.part_sizes().unwrap(), (3, 4));
+    assert_eq!(lua.new_table().unwrap().part_sizes().unwrap(), (0, 0));
+}
+
+#[test]
+fn yield_across_rust() {
+    let lua = Lua::with_open_libs();
+    let g = lua.global();
+    g.set(
+        "ryield",
+        lua.new_function(|lua, (a, b): (i64, i64)| -> () { lua.yield_with((a + b, a * b)) })
+            .unwrap(),
+    )
+    .unwrap();
+    g.set(
+        "rcall",
+        lua.new_function(|lua, f: LuaFunction| {
+            lua.pcall_k(f, (), |_, results| {
+                results.map(|r| match r[0] {
+                    LuaValue::Integer(n) => n + 1,
+                    _ => 0,
+                })
+            })
+        })
+        .unwrap(),
+    )
+    .unwrap();
+    // the arguments of caller are still alive after the call
+    g.set(
+        "rcheck",
+        lua.new_function(|lua, (_, f): (LuaTable, LuaFunction)| {
+            lua.pcall_k(f, (), |s, results| {
+                assert_eq!(s.arg_val(1).unwrap().type_of(), LuaType::Table);
+                match results.unwrap()[0] {
+                    LuaValue::Integer(n) => n + 1,
+                    _ => 0,
+                }
+            })
+        })
+        .unwrap(),
+    )
+    .unwrap();
+
+    lua.do_string(
+        r#"
+        local co = coroutine.wrap(function(a, b)
+            local r = ryield(a, b)
+            return r * 10
+        end)
+        local s, p = co(2, 3)
+        assert(s == 5 and p == 6)
+        assert(co(7) == 70)
+
+        co = coroutine.wrap(function()
+            return rcall(function() return coroutine.yield('inner') end)
+        end)
+        assert(co() == 'inner')
+        assert(co(41) == 42)
+
+        assert(rcall(function() return 1 end) == 2)
+        assert(not pcall(rcall, function() error('oops') end))
+        assert(rcheck({}, function() return 1 end) == 2)
+        "#,
+        None,
+    )
+    .unwrap();
+}
+
+#[cfg(feature = "either")]
+#[test]
+fn either_results() {
+    use either::Either;
+
+    let lua = Lua::with_open_libs();
+    lua.global()
+        .set(
+            "div",
+            lua.new_function(|_, (a, b): (i64, i64)| {
+                if b == 0 {
+                    Either::Right("err")
+                } else {
+                    Either::Left((a / b, a % b))
+                }
+            })
+            .unwrap(),
+        )
+        .unwrap();
+    lua.do_string(
+        r#"
+        assert(select('#', div(7, 2)) == 2)
+        local q, r = div(7, 2)
+        assert(q == 3 and r == 1)
+        assert(select('#', div(1, 0)) == 1)
+        assert(div(1, 0) == 'err')
+        "#,
+        None,
+    )
+    .unwrap();
+}
+
+#[test]
+fn line_hook() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let lua = Lua::with_open_libs();
+    let lines = Rc::new(RefCell::new(vec![]));
+    let collected = lines.clone();
+    lua.on_line(move |_, source, line| {
+        if source.contains("script") {
+            collected.borrow_mut().push(line);
+        }
+        HookAction::Continue
+    })
+    .unwrap();
+    lua.do_string("local a = 1\nlocal b = 2\n\nreturn a + b", Some("script"))
+        .unwrap();
+    lua.remove_hook();
+    assert_eq!(lines.borrow().as_slice(), &[1, 2, 4]);
+
+    lua.on_line(|_, _, line| {
+        if line == 2 {
+            HookAction::Break
+        } else {
+            HookAction::Continue
+        }
+    })
+    .unwrap();
+    let err = lua
+        .do_string("local a = 1\nlocal b = 2", Some("script"))
+        .unwrap_err();
+    assert!(err.to_string().contains("break at"));
+    lua.remove_hook();
+}
+
+#[test]
+fn debug_hook() {
+    use ezlua::ffi::LUA_HOOKCALL;
+    use ezlua::luaapi::HookMask;
+
+    let lua = Lua::with_open_libs();
+    let calls = Rc::new(RefCell::new(0));
+    let counter = calls.clone();
+    lua.set_hook(HookMask::MASKCALL, 0, move |_, ar| {
+        if ar.event == LUA_HOOKCALL {
+            *counter.borrow_mut() += 1;
+        }
+        Ok(())
+    })
+    .unwrap();
+    lua.do_string("local function f() end f() f()", None)
+        .unwrap();
+    lua.remove_hook();
+    assert!(*calls.borrow() >= 2);
+
+    lua.set_hook(HookMask::MASKCOUNT, 100, |_, _| {
+        Err(LuaError::runtime("step limit"))
+    })
+    .unwrap();
+    let err = lua.do_string("while true do end", None).unwrap_err();
+    assert!(err.to_string().contains("step limit"));
+    lua.remove_hook();
+}
+
+#[test]
+fn value_type_name() {
+    let lua = Lua::with_open_libs();
+    let names = [
+        (LuaValue::None, "none"),
+        (LuaValue::Nil, "nil"),
+        (LuaValue::Bool(true), "boolean"),
+        (LuaValue::Integer(1), "integer"),
+        (LuaValue::Number(1.0), "number"),
+        (
+            LuaValue::light_userdata(core::ptr::null::<()>()),
+            "lightuserdata",
+        ),
+        (lua.new_value("s").unwrap(), "string"),
+        (lua.new_value(lua.new_table().unwrap()).unwrap(), "table"),
+        (lua.global().get("print").unwrap().into_value(), "function"),
+        (LuaValue::Thread(lua.main_state()), "thread"),
+        (
+            lua.new_value(lua.new_userdata(Test { a: 0 }).unwrap())
+                .unwrap(),
+            "userdata",
+        ),
+    ];
+    for (val, name) in names {
+        assert_eq!(val.type_name(), name);
+    }
+}
+
+#[test]
+fn filtered_frames() {
+    let lua = Lua::with_open_libs();
+    lua.global()
+        .set(
+            "capture",
+            lua.new_closure(|lua: &LuaState| {
+                let all = lua.frames(0, false);
+                let filtered = lua.frames(0, true);
+                assert_eq!(all[0].what, "C");
+                assert_eq!(all.len(), filtered.len() + 1);
+                assert!(filtered.iter().all(|f| f.what != "C"));
+                assert_eq!(filtered[0].name.as_deref(), Some("outer"));
+
+                let tb = lua.traceback_filtered("oops", 0, true);
+                assert!(tb.starts_with("oops\nstack traceback:"));
+                assert!(!tb.contains("[C]"));
+                assert!(lua.traceback_filtered("oops", 0, false).contains("[C]"));
+                Err::<(), _>("error through rust")
+            })
+            .unwrap(),
+        )
+        .unwrap();
+    let err = lua
+        .do_string("function outer() capture() end\nouter()", Some("frames"))
+        .unwrap_err();
+    assert!(err.to_string().contains("error through rust"));
+}
+
+#[test]
+fn instrument_function() {
+    let lua = Lua::with_open_libs();
+    let add = lua
+        .load("return function(a, b) return a + b, a * b end", None)
+        .unwrap()
+        .pcall::<_, LuaFunction>(())
+        .unwrap();
+    let (wrapper, counter) = add.instrument().unwrap();
+    lua.global().set("add", wrapper).unwrap();
+    lua.do_string(
+        r#"
+        for i = 1, 10 do
+            local s, p = add(i, 2)
+            assert(s == i + 2 and p == i * 2)
+        end
+        "#,
+        None,
+    )
+    .unwrap();
+    assert_eq!(counter.count(), 10);
+}
+
+#[test]
+fn table_read_range() {
+    let lua = Lua::with_open_libs();
+    let t = lua.new_val((1..=10).collect::<Vec<i64>>()).unwrap();
+    let t = t.as_table().unwrap();
+    assert_eq!(t.read_range::<i64>(3, 6).unwrap(), [3, 4, 5, 6]);
+    assert_eq!(
+        t.read_range::<Option<i64>>(9, 11).unwrap(),
+        [Some(9), Some(10), None]
+    );
+    assert!(t.read_range::<i64>(5, 4).unwrap().is_empty());
+    assert!(t.read_range::<i64>(i64::MAX, i64::MIN).unwrap().is_empty());
+    t.read_range::<i64>(i64::MIN, i64::MAX).unwrap_err();
+    t.read_range::<i64>(0, i64::MAX).unwrap_err();
+    assert_eq!(
+        t.read_range::<Option<i64>>(i64::MAX - 1, i64::MAX).unwrap(),
+        [None, None]
+    );
+    t.raw_seti(2, "str").unwrap();
+    t.read_range::<i64>(1, 3).unwrap_err();
+}
+
+#[test]
+fn table_integer_index() {
+    let lua = Lua::with_open_libs();
+    let t = lua.new_table().unwrap();
+    t.push(1).unwrap();
+    t.push(3).unwrap();
+    let at: usize = 2;
+    t.raw_insert(at, 2).unwrap();
+    t.raw_insert(1i64, 0).unwrap();
+    t.raw_insert(u64::MAX, 0).unwrap_err();
+    assert_eq!(t.raw_len_i64(), 4);
+    assert_eq!(t.read_range::<i64>(1, 4).unwrap(), [0, 1, 2, 3]);
+
+    // indices beyond u32 go to the hash part and don't affect the border
+    let large = 1i64 << 40;
+    t.raw_seti(large, "far").unwrap();
+    assert_eq!(t.raw_geti(large).unwrap().to_str(), Some("far"));
+    assert_eq!(t.raw_len_i64(), 4);
+    t.push(4).unwrap();
+    assert_eq!(t.raw_geti(5).unwrap().to_integer(), 4);
+}
+
+#[test]
+fn require_caching() {
+    let lua = Lua::with_open_libs();
+    lua.do_string(
+        "counter = 0 package.preload.counted = function() counter = counter + 1 return counter end",
+        None,
+    )
+    .unwrap();
+    let counter = || lua.global().get("counter").unwrap().to_integer();
+
+    lua.do_string("require 'counted' require 'counted'", None)
+        .unwrap();
+    assert_eq!(counter(), 1);
+
+    lua.set_require_caching(false).unwrap();
+    lua.do_string("require 'counted' require 'counted'", None)
+        .unwrap();
+    assert_eq!(counter(), 3);
+    lua.do_string("assert(require 'string' == string)", None)
+        .unwrap();
+
+    lua.set_require_caching(true).unwrap();
+    lua.do_string("require 'counted' require 'counted'", None)
+        .unwrap();
+    assert_eq!(counter(), 3);
+}
+
+#[test]
+fn owned_value() {
+    let owned = {
+        let lua = Lua::with_open_libs();
+        let t = lua
+            .load(
+                "return {1, 'two', 3.5, nested = {flag = true, [10] = 'ten'}, f = print}",
+                None,
+            )
+            .unwrap()
+            .pcall::<_, ValRef>(())
+            .unwrap();
+        t.to_owned_value().unwrap()
+    };
+
+    let OwnedValue::Table { array, hash } = owned else {
+        panic!("table expected");
+    };
+    assert_eq!(
+        array,
+        [
+            OwnedValue::Integer(1),
+            OwnedValue::String("two".into()),
+            OwnedValue::Number(3.5)
+        ]
+    );
+    assert_eq!(
+        hash[&OwnedKey::String("f".into())],
+        OwnedValue::Opaque(LuaType::Function)
+    );
+    let OwnedValue::Table { array, hash } = &hash[&OwnedKey::String("nested".into())] else {
+        panic!("nested table expected");
+    };
+    assert!(array.is_empty());
+    assert_eq!(
+        hash[&OwnedKey::String("flag".into())],
+        OwnedValue::Bool(true)
+    );
+    assert_eq!(
+        hash[&OwnedKey::Integer(10)],
+        OwnedValue::String("ten".into())
+    );
+
+    let lua = Lua::with_open_libs();
+    let t = lua.new_table().unwrap();
+    t.set("self", t.clone()).unwrap();
+    t.to_owned_value().unwrap_err();
+}
+
+#[test]
+fn display_error() {
+    #[derive(Debug)]
+    struct Inner;
+
+    impl std::fmt::Display for Inner {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "inner cause")
+        }
+    }
+
+    impl std::error::Error for Inner {}
+
+    #[derive(Debug)]
+    struct Outer(Inner);
+
+    impl std::fmt::Display for Outer {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "outer failure")
+        }
+    }
+
+    impl std::error::Error for Outer {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    let lua = Lua::with_open_libs();
+    lua.global()
+        .set(
+            "fallible",
+            lua.new_closure(|ok: bool| DisplayError(if ok { Ok(1) } else { Err(Outer(Inner)) }))
+                .unwrap(),
+        )
+        .unwrap();
+    lua.do_string(
+        r#"
+        assert(fallible(true) == 1)
+        local ok, err = pcall(fallible, false)
+        assert(not ok and err == 'outer failure: inner cause', err)
+        "#,
+        None,
+    )
+    .unwrap();
+}
+
+#[test]
+fn error_source() {
+    use std::error::Error as _;
+
+    let lua = Lua::with_open_libs();
+    let err = lua.load_file("tests/not-exists.lua").unwrap_err();
+    let io = err
+        .source()
+        .and_then(|e| e.downcast_ref::<std::io::Error>())
+        .expect("io error source");
+    assert_eq!(io.kind(), std::io::ErrorKind::NotFound);
+    assert!(LuaError::runtime("plain").source().is_none());
+
+    let err = LuaError::external(std::fmt::Error);
+    assert!(err.source().unwrap().is::<std::fmt::Error>());
+}
+
+#[test]
+fn number_format() {
+    let lua = Lua::with_open_libs();
+    let tostring = |v: f64| lua.new_val(v).unwrap().tostring().into_owned();
+
+    // the same as lua by default
+    assert_eq!(lua.number_format(), NumberFormat::Lua);
+    assert_eq!(tostring(0.1 + 0.2), "0.3");
+    assert_eq!(tostring(1.0), "1.0");
+    assert_eq!(tostring(1.0 / 3.0), "0.33333333333333");
+    assert_eq!(lua.new_val(3).unwrap().tostring(), "3");
+    let lua_tostring: LuaFunction = lua.global().get("tostring").unwrap().try_into().unwrap();
+    for n in [
+        0.1 + 0.2,
+        1e300,
+        -1.5e-300,
+        1e15,
+        1e14,
+        123456.789,
+        0.0001,
+        1e-5,
+        f64::MAX,
+    ] {
+        assert_eq!(
+            NumberFormat::Lua.format(n),
+            lua_tostring.pcall::<_, String>(n).unwrap()
+        );
+    }
+
+    lua.set_number_format(NumberFormat::Shortest).unwrap();
+    assert_eq!(lua.number_format(), NumberFormat::Shortest);
+    assert_eq!(tostring(0.1 + 0.2), "0.30000000000000004");
+    assert_eq!(tostring(0.1), "0.1");
+    assert_eq!(tostring(1.0), "1.0");
+    assert_eq!(tostring(1.0 / 3.0), "0.3333333333333333");
+    assert_eq!(tostring(1e300), "1e+300");
+    assert_eq!(tostring(-1.5e-300), "-1.5e-300");
+    assert_eq!(tostring(1e17), "1e+17");
+    assert_eq!(tostring(1e16), "10000000000000000.0");
+    assert_eq!(tostring(0.0001), "0.0001");
+    assert_eq!(tostring(0.00001234), "1.234e-05");
+    assert_eq!(tostring(f64::MAX), "1.7976931348623157e+308");
+    assert_eq!(tostring(5e-324), "5e-324");
+
+    lua.set_number_format(NumberFormat::Precision(17)).unwrap();
+    assert_eq!(tostring(0.1), "0.10000000000000001");
+    assert_eq!(tostring(1e300), "1.0000000000000001e+300");
+    assert_eq!(tostring(2.0), "2.0");
+
+    lua.set_number_format(NumberFormat::Precision(3)).unwrap();
+    assert_eq!(tostring(1234.5), "1.23e+03");
+    assert_eq!(tostring(0.000125), "0.000125");
+
+    lua.set_number_format(NumberFormat::Lua).unwrap();
+    assert_eq!(tostring(0.1 + 0.2), "0.3");
+}
+
+#[test]
+fn checked_integer() {
+    let lua = Lua::with_open_libs();
+    let checked = |src: &str| lua.load(src, None).unwrap();
+
+    let Checked(n) = checked("return 255").pcall::<_, Checked<u8>>(()).unwrap();
+    assert_eq!(n, 255);
+    let Checked(n) = checked("return 2.0").pcall::<_, Checked<i64>>(()).unwrap();
+    assert_eq!(n, 2);
+
+    let err = checked("return 256")
+        .pcall::<_, Checked<u8>>(())
+        .unwrap_err();
+    assert!(matches!(err, LuaError::Convert(_)), "{err:?}");
+    let err = checked("return -1")
+        .pcall::<_, Checked<u32>>(())
+        .unwrap_err();
+    assert!(matches!(err, LuaError::Convert(_)), "{err:?}");
+    let err = checked("return 1.5")
+        .pcall::<_, Checked<i64>>(())
+        .unwrap_err();
+    assert!(matches!(err, LuaError::Convert(_)), "{err:?}");
+    let err = checked("return 2^63")
+        .pcall::<_, Checked<i64>>(())
+        .unwrap_err();
+    assert!(matches!(err, LuaError::Convert(_)), "{err:?}");
+
+    // the unchecked conversion truncates silently
+    assert_eq!(checked("return 1.5").pcall::<_, i64>(()).unwrap(), 1);
+}
+
+#[test]
+fn table_retain() {
+    let lua = Lua::with_open_libs();
+    let t = lua
+        .load(
+            "return { 1, false, 'x', a = 0, b = '', c = 'keep', d = false, e = 10 }",
+            None,
+        )
+        .unwrap()
+        .pcall::<_, LuaTable>(())
+        .unwrap();
+    t.retain(|_, v| match v.type_of() {
+        LuaType::Boolean => v.to_bool(),
+        LuaType::Number => v.to_integer() != 0,
+        LuaType::String => v.to_str() != Some(""),
+        _ => true,
+    })
+    .unwrap();
+
+    let mut keys = t
+        .pairs()
+        .unwrap()
+        .map(|(k, _)| match k {
+            LuaValue::String(s) => s.to_string_lossy().into_owned(),
+            k => format!("{k:?}"),
+        })
+        .collect::<Vec<_>>();
+    keys.sort();
+    assert_eq!(keys, ["Integer(1)", "Integer(3)", "c", "e"]);
+    assert_eq!(t.entry_count(), 4);
+
+    // more entries than the stack can hold
+    let large: LuaTable = lua
+        .load(
+            "local t = {} for i = 1, 1200000 do t[i] = i end return t",
+            None,
+        )
+        .unwrap()
+        .pcall(())
+        .unwrap();
+    large.retain(|_, v| v.to_integer() % 2 == 0).unwrap();
+    assert_eq!(large.entry_count(), 600000);
+    assert!(large.raw_geti(1).unwrap().is_nil());
+    assert_eq!(large.raw_geti(1200000).unwrap().to_integer(), 1200000);
+}
+
+#[test]
+fn stateless_function() {
+    let lua = Lua::with_open_libs();
+    let g = lua.global();
+    g.set("inc", lua.new_fn(|x: i64| x + 1).unwrap()).unwrap();
+    g.set("swap", lua.new_fn(|(a, b): (i64, String)| (b, a)).unwrap())
+        .unwrap();
+    g.set("answer", lua.new_fn(|()| 42).unwrap()).unwrap();
+    lua.do_string(
+        r#"
+        assert(inc(1) == 2)
+        local a, b = swap(1, 'x')
+        assert(a == 'x' and b == 1)
+        assert(answer() == 42)
+        "#,
+        None,
+    )
+    .unwrap();
+}
+
+#[test]
+fn char_convert() {
+    let lua = Lua::with_open_libs();
+    let g = lua.global();
+    g.set("a", 'a').unwrap();
+    g.set("euro", '€').unwrap();
+    lua.do_string("assert(a == 'a' and euro == '€' and #euro == 3)", None)
+        .unwrap();
+
+    let eval = |src: &str| lua.load(src, None).unwrap().pcall::<_, char>(());
+    assert_eq!(eval("return 'x'").unwrap(), 'x');
+    assert_eq!(eval("return '€'").unwrap(), '€');
+    assert_eq!(eval("return 0x20AC").unwrap(), '€');
+    assert!(eval("return ''").is_err());
+    assert!(eval("return 'ab'").is_err());
+    assert!(eval("return '\\xff'").is_err());
+    assert!(eval("return 0xD800").is_err());
+    assert!(eval("return 1.5").is_err());
+    assert!(eval("return {}").is_err());
+}
+
+#[test]
+fn table_value_map() {
+    let lua = Lua::with_open_libs();
+    let opts = lua
+        .load(
+            "return { name = 'app', port = 8080, ratio = 0.5, debug = true, tags = {'a'} }",
+            None,
+        )
+        .unwrap()
+        .pcall::<_, LuaTable>(())
+        .unwrap();
+    let map = opts.to_value_map().unwrap();
+    assert_eq!(map.len(), 5);
+    assert!(matches!(&map["name"], LuaValue::String(s) if s.to_str() == Some("app")));
+    assert!(matches!(map["port"], LuaValue::Integer(8080)));
+    assert!(matches!(map["ratio"], LuaValue::Number(n) if n == 0.5));
+    assert!(matches!(map["debug"], LuaValue::Bool(true)));
+    assert!(matches!(&map["tags"], LuaValue::Table(t) if t.raw_len() == 1));
+
+    opts.raw_seti(1, "positional").unwrap();
+    assert!(opts.to_value_map().is_err());
+}
+
+#[test]
+fn net_addr() {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    let lua = Lua::with_open_libs();
+    let g = lua.global();
+    g.set("v4", Ipv4Addr::LOCALHOST).unwrap();
+    g.set("v6", IpAddr::V6(Ipv6Addr::LOCALHOST)).unwrap();
+    g.set("sock", SocketAddr::from(([10, 0, 0, 1], 8080)))
+        .unwrap();
+    lua.do_string(
+        "assert(v4 == '127.0.0.1' and v6 == '::1' and sock == '10.0.0.1:8080')",
+        None,
+    )
+    .unwrap();
+
+    assert_eq!(
+        g.get("v4").unwrap().cast::<Ipv4Addr>().unwrap(),
+        Ipv4Addr::LOCALHOST
+    );
+    assert_eq!(
+        g.get("v6").unwrap().cast::<Ipv6Addr>().unwrap(),
+        Ipv6Addr::LOCALHOST
+    );
+    assert_eq!(
+        g.get("sock").unwrap().cast::<SocketAddr>().unwrap(),
+        SocketAddr::from(([10, 0, 0, 1], 8080))
+    );
+    let addr = lua
+        .new_val("[::1]:443")
+        .unwrap()
+        .cast::<SocketAddr>()
+        .unwrap();
+    assert_eq!(addr.port(), 443);
+
+    let err = lua
+        .new_val("localhost")
+        .unwrap()
+        .cast::<IpAddr>()
+        .unwrap_err();
+    assert!(matches!(err, LuaError::Convert(_)), "{err:?}");
+    assert!(g.get("v6").unwrap().cast::<Ipv4Addr>().is_err());
+}
+
+#[test]
+fn register_module_lazy() {
+    use std::{cell::Cell, rc::Rc};
+
+    let lua = Lua::with_open_libs();
+    let inits = Rc::new(Cell::new(0));
+    let counter = inits.clone();
+    lua.register_module_lazy("heavy", move |s| {
+        counter.set(counter.get() + 1);
+        let m = s.new_table()?;
+        m.set("answer", 42)?;
+        Ok(m)
+    })
+    .unwrap();
+    assert_eq!(inits.get(), 0);
+
+    lua.do_string("assert(package.preload.heavy)", None)
+        .unwrap();
+    assert_eq!(inits.get(), 0);
+
+    lua.do_string(
+        "assert(require 'heavy'.answer == 42 and require 'heavy' == require 'heavy')",
+        None,
+    )
+    .unwrap();
+    assert_eq!(inits.get(), 1);
+}
+
+#[test]
+fn pcall_traced() {
+    let lua = Lua::with_open_libs();
+    let func = lua
+        .load(
+            "local function inner() error('rpc failed') end return function() inner() end",
+            Some("=rpc"),
+        )
+        .unwrap()
+        .pcall::<_, LuaFunction>(())
+        .unwrap();
+
+    let err = func.pcall_traced::<_, ()>(()).unwrap_err();
+    assert!(
+        matches!(&err.error, LuaError::Runtime(msg) if msg == "rpc:1: rpc failed"),
+        "{:?}",
+        err.error
+    );
+    assert!(err.traceback.starts_with("stack traceback:"));
+    assert!(err.traceback.contains("inner"), "{}", err.traceback);
+    assert!(err.to_string().ends_with(&err.traceback));
+
+    let n: i32 = lua
+        .load("return 1 + 1", None)
+        .unwrap()
+        .pcall_traced(())
+        .unwrap();
+    assert_eq!(n, 2);
+
+    lua.set_capture_traceback(false).unwrap();
+    let err = func.pcall_traced::<_, ()>(()).unwrap_err();
+    assert!(err.traceback.is_empty());
+}
+
+#[test]
+fn intern_string() {
+    let lua = Lua::with_open_libs();
+    let long = "event:".repeat(20);
+    let a = lua.intern(&long).unwrap();
+    let b = lua.intern(&long).unwrap();
+    assert!(a.raw_equal(&b));
+    assert_eq!(a.to_pointer(), b.to_pointer());
+    assert_eq!(a.to_str(), Some(long.as_str()));
+    assert!(!a.raw_equal(&lua.intern("other").unwrap()));
+
+    let t = lua.new_table().unwrap();
+    t.raw_set(&*a, 1).unwrap();
+    assert_eq!(t.raw_get(&*b).unwrap().to_integer(), 1);
+
+    lua.clear_interned().unwrap();
+    let c = lua.intern(&long).unwrap();
+    assert!(a.raw_equal(&c));
+}
+
+#[test]
+fn value_error_pair() {
+    let lua = Lua::with_open_libs();
+    let open = lua
+        .load(
+            r#"
+            return function(name)
+                if name == 'ok' then return 'content' end
+                if name == 'silent' then return nil end
+                if name == 'exists' then return false end
+                return nil, 'failed: ' .. name
+            end
+            "#,
+            None,
+        )
+        .unwrap()
+        .pcall::<_, LuaFunction>(())
+        .unwrap();
+
+    let res: Result<String, String> = open.pcall("ok").unwrap();
+    assert_eq!(res, Ok("content".into()));
+    let res: Result<String, String> = open.pcall("missing").unwrap();
+    assert_eq!(res, Err("failed: missing".into()));
+    let res: Result<String, String> = open.pcall("silent").unwrap();
+    assert_eq!(res, Err("unknown error".into()));
+    // false is a value rather than an error
+    let res: Result<bool, String> = open.pcall("exists").unwrap();
+    assert_eq!(res, Ok(false));
+    // the conversion error of the value is raised
+    assert!(open.pcall::<_, Result<i64, String>>("ok").is_err());
+
+    lua.global()
+        .set(
+            "wrap",
+            lua.new_fn(|r: Result<i64, String>| r.map_or_else(|e| e.len() as i64, |n| n * 2))
+                .unwrap(),
+        )
+        .unwrap();
+    lua.do_string("assert(wrap(21) == 42 and wrap(nil, 'abc') == 3)", None)
+        .unwrap();
+}
+
+#[test]
+fn profiler() {
+    let lua = Lua::with_open_libs();
+    assert!(lua.take_profile().is_empty());
+
+    lua.start_profiler(100).unwrap();
+    lua.do_string(
+        r#"
+        local function fib(n)
+            if n < 2 then return n end
+            return fib(n - 1) + fib(n - 2)
+        end
+        assert(fib(18) == 2584)
+        "#,
+        Some("=fib"),
+    )
+    .unwrap();
+    lua.remove_hook();
+
+    let samples = lua.take_profile();
+    assert!(samples.len() > 10, "{}", samples.len());
+    let depth = samples.iter().map(Vec::len).max().unwrap();
+    assert!(depth > 5 && depth <= 20, "{depth}");
+    assert!(samples
+        .iter()
+        .all(|stack| stack.iter().all(|frame| frame.starts_with("fib:"))));
+    let collapsed = samples[0].join(";");
+    assert!(collapsed.starts_with("fib:6"), "{collapsed}");
+
+    // the samples are taken
+    assert!(lua.take_profile().is_empty());
+}
+
+#[test]
+fn open_std_libs() {
+    let lua = Lua::new();
+    lua.open_std_libs(StdLibSet::STRING | StdLibSet::MATH)
+        .unwrap();
+    let g = lua.global();
+    assert!(g.get("string").unwrap().is_table());
+    assert!(g.get("math").unwrap().is_table());
+    assert!(g.get("os").unwrap().is_nil());
+    assert!(g.get("print").unwrap().is_nil());
+
+    lua.open_std_lib(StdLib::Base).unwrap();
+    lua.do_string(
+        "assert(string.rep('a', 3) == 'aaa' and math.max(1, 2) == 2)",
+        None,
+    )
+    .unwrap();
+}
+
+#[test]
+fn scope_userdata_ref() {
+    struct Config {
+        name: String,
+    }
+
+    impl UserData for Config {
+        type Trans = ezlua::userdata::MaybePointer<Self>;
+
+        fn getter(fields: UserdataRegistry<Self>) -> LuaResult<()> {
+            fields.add_field_get("name", |_, this| this.name.clone())?;
+            Ok(())
+        }
+
+        fn methods(_: UserdataRegistry<Self>) -> LuaResult<()> {
+            Ok(())
+        }
+    }
+
+    let lua = Lua::with_open_libs();
+    let config = Config {
+        name: "local".into(),
+    };
+    lua.scope(|scope| {
+        let udata = scope.create_userdata_ref(&config)?;
+        lua.global().set("config", udata)?;
+        lua.do_string("assert(config.name == 'local')", None)
+    })
+    .unwrap();
+    lua.do_string("return config.name", None).unwrap_err();
+    assert_eq!(config.name, "local");
+}
+
+#[test]
+fn arg_error_location() {
+    let lua = Lua::with_open_libs();
+    lua.global()
+        .set("add", lua.new_closure(|a: i64, b: i64| a + b).unwrap())
+        .unwrap();
+    let script = "local x = 1\nreturn add(x, {})";
+
+    let message = || {
+        let err = lua.do_string(script, Some("@script.lua")).unwrap_err();
+        let msg = format!("{err:?}");
+        msg.lines().next().unwrap_or_default().to_owned()
+    };
+    assert!(message().starts_with("convert: cast #2"));
+
+    lua.set_arg_error_location(true).unwrap();
+    assert!(message().starts_with("convert: script.lua:2: cast #2"));
+}
+
+#[test]
+fn table_map_values() {
+    let lua = Lua::with_open_libs();
+    let t = lua
+        .load("return {'a', 'b', name = 'ezlua', count = 3}", None)
+        .unwrap()
+        .pcall::<_, LuaTable>(())
+        .unwrap();
+    t.map_values(|lua, v| {
+        Ok(match v.to_str() {
+            Some(s) if v.type_of() == LuaType::String => lua.new_value(s.to_uppercase())?,
+            _ => v.into_value(),
+        })
+    })
+    .unwrap();
+    assert_eq!(t.raw_get(1).unwrap().to_str(), Some("A"));
+    assert_eq!(t.raw_get(2).unwrap().to_str(), Some("B"));
+    assert_eq!(t.raw_get("name").unwrap().to_str(), Some("EZLUA"));
+    assert_eq!(t.raw_get("count").unwrap().to_integer(), 3);
+
+    // more entries than the stack can hold
+    let large: LuaTable = lua
+        .load(
+            "local t = {} for i = 1, 1200000 do t[i] = i end return t",
+            None,
+        )
+        .unwrap()
+        .pcall(())
+        .unwrap();
+    large
+        .map_values(|lua, v| lua.new_value(v.to_integer() * 2))
+        .unwrap();
+    assert_eq!(large.raw_geti(1).unwrap().to_integer(), 2);
+    assert_eq!(large.raw_geti(1200000).unwrap().to_integer(), 2400000);
+}
+
+#[test]
+fn minimal_state() {
+    let lua = Lua::new_with(StdLibSet::BASE | StdLibSet::TABLE | StdLibSet::STRING).unwrap();
+    let g = lua.global();
+    assert!(g.get("io").unwrap().is_nil());
+    assert!(g.get("os").unwrap().is_nil());
+    assert!(g.get("require").unwrap().is_nil());
+    lua.do_string("assert(table.concat({'a', 'b'}):upper() == 'AB')", None)
+        .unwrap();
+}
+
+#[test]
+fn gc_observer() {
+    let lua = Lua::with_open_libs();
+    let events = Rc::new(RefCell::new(Vec::new()));
+    let events2 = events.clone();
+    lua.set_gc_observer(move |phase, used| events2.borrow_mut().push((phase, used)))
+        .unwrap();
+
+    lua.do_string("for i = 1, 100000 do local t = {i, tostring(i)} end", None)
+        .unwrap();
+    assert!(events.borrow().iter().any(|(p, _)| *p == GcPhase::Cycle));
+    // the memory in use is reported even inside the finalizer
+    assert!(events.borrow().iter().all(|(_, used)| *used > 0));
+
+    lua.gc_step().unwrap();
+    let (phase, used) = *events.borrow().last().unwrap();
+    assert_eq!(phase, GcPhase::Step);
+    assert!(used > 0);
+
+    lua.remove_gc_observer();
+    let count = events.borrow().len();
+    lua.gc_collect().unwrap();
+    lua.gc_step().unwrap();
+    assert_eq!(events.borrow().len(), count);
+
+    // the replaced observer hands over the memory counter
+    let used = Rc::new(Cell::new(0));
+    let used2 = used.clone();
+    lua.set_gc_observer(|_, _| {}).unwrap();
+    lua.set_gc_observer(move |_, n| used2.set(n)).unwrap();
+    lua.gc_collect().unwrap();
+    lua.gc_step().unwrap();
+    assert!(used.get() > 0);
+    assert!(used.get().abs_diff(lua.used_memory()) < 1024);
+}
+
+#[test]
+fn function_apply() {
+    let lua = Lua::with_open_libs();
+    let concat = lua
+        .load(
+            "return function(a, b, ...) return a .. b, select('#', ...) end",
+            None,
+        )
+        .unwrap()
+        .pcall::<_, LuaFunction>(())
+        .unwrap();
+    let args = lua.new_val(vec!["foo", "bar"]).unwrap();
+    let args = args.as_table().unwrap();
+    assert_eq!(
+        concat.apply::<(String, i32)>(args).unwrap(),
+        ("foobar".into(), 0)
+    );
+
+    args.push("baz").unwrap();
+    assert_eq!(concat.apply::<(String, i32)>(args).unwrap().1, 1);
+}
+
+#[test]
+fn string_format() {
+    let lua = Lua::with_open_libs();
+    assert_eq!(lua.format("%.2f", 3.14159).unwrap(), "3.14");
+    assert_eq!(
+        lua.format("%s=%q", ("name", "a\"b\n")).unwrap(),
+        "name=\"a\\\"b\\\n\""
+    );
+    lua.format("%d", "x").unwrap_err();
+
+    let lua = Lua::new();
+    lua.format("%d", 1).unwrap_err();
+}
+
+#[test]
+fn deque_and_list() {
+    use std::collections::{LinkedList, VecDeque};
+
+    let lua = Lua::with_open_libs();
+    let mut deque = VecDeque::from([2, 3]);
+    deque.push_front(1);
+    let val = lua.new_val(deque.clone()).unwrap();
+    assert_eq!(val.as_table().unwrap().raw_len(), 3);
+    assert_eq!(val.cast_into::<VecDeque<i32>>().unwrap(), deque);
+
+    let list = ["a", "b", "c"]
+        .into_iter()
+        .map(String::from)
+        .collect::<LinkedList<_>>();
+    let val = lua.new_val(list.clone()).unwrap();
+    assert_eq!(
+        val.as_table().unwrap().raw_geti(3).unwrap().to_str(),
+        Some("c")
+    );
+    assert_eq!(val.cast_into::<LinkedList<String>>().unwrap(), list);
+}
+
+#[test]
+fn capture_traceback() {
+    let lua = Lua::with_open_libs();
+    let message = || format!("{:?}", lua.do_string("error('oops')", None).unwrap_err());
+    assert!(message().contains("stack traceback"));
+
+    lua.set_capture_traceback(false).unwrap();
+    let msg = message();
+    assert!(
+        msg.contains("oops") && !msg.contains("stack traceback"),
+        "{msg}"
+    );
+    assert_eq!(
+        lua.load("return ...", None)
+            .unwrap()
+            .pcall::<_, i32>(1)
+            .unwrap(),
+        1
+    );
+
+    lua.set_capture_traceback(true).unwrap();
+    assert!(message().contains("stack traceback"));
+}
+
+#[test]
+fn string_as_number() {
+    let lua = Lua::with_open_libs();
+    let number = |s: &[u8]| {
+        let s = lua.new_val(s).unwrap();
+        let s: LuaString = s.try_into().unwrap();
+        s.as_number()
+    };
+    assert!(matches!(number(b"42"), Some(LuaValue::Integer(42))));
+    assert!(matches!(number(b" 0x1F "), Some(LuaValue::Integer(31))));
+    assert!(matches!(number(b"1.5e3"), Some(LuaValue::Number(n)) if n == 1500.0));
+    assert!(matches!(number(b"0x1p4"), Some(LuaValue::Number(n)) if n == 16.0));
+    assert!(number(b"abc").is_none());
+    assert!(number(b"12abc").is_none());
+    assert!(number(b"1\x002").is_none());
+    assert!(number(b"").is_none());
+}
+
+#[test]
+fn boxed_functions() {
+    let lua = Lua::with_open_libs();
+    let g = lua.global();
+    for (name, factor) in [("double", 2), ("triple", 3)] {
+        let fun: BoxedFunction = Box::new(move |_, args| {
+            Ok(args
+                .0
+                .into_iter()
+                .map(|v| match v {
+                    LuaValue::Integer(i) => LuaValue::Integer(i * factor),
+                    v => v,
+                })
+                .collect::<Vec<_>>()
+                .into())
+        });
+        g.set(name, lua.new_boxed_function(fun).unwrap()).unwrap();
+    }
+    lua.do_string(
+        r#"
+        local a, b = double(1, 2)
+        assert(a == 2 and b == 4)
+        assert(triple(3) == 9)
+        assert(select('#', double()) == 0)
+        "#,
+        None,
+    )
+    .unwrap();
+}
+
+#[test]
+fn table_contains_key() {
+    let lua = Lua::with_open_libs();
+    let t = lua
+        .load(
+            "return setmetatable({1, a = false, b = nil}, {__index = function() return 0 end})",
+            None,
+        )
+        .unwrap()
+        .pcall::<_, LuaTable>(())
+        .unwrap();
+    assert!(t.contains_key(1).unwrap());
+    assert!(t.contains_key("a").unwrap());
+    assert!(!t.contains_key("b").unwrap());
+    assert!(!t.contains_key("c").unwrap());
+    assert!(!t.contains_key(()).unwrap());
+
+    let top = lua.stack_top();
+    t.contains_key("a").unwrap();
+    assert_eq!(lua.stack_top(), top);
+}
+
+#[test]
+fn instruction_limit() {
+    let lua = Lua::with_open_libs();
+    let top = lua.stack_top();
+    lua.set_instruction_limit(100000).unwrap();
+    let err = lua.do_string("while true do end", None).unwrap_err();
+    assert!(err.to_string().contains("instruction limit exceeded"));
+    assert_eq!(lua.stack_top(), top);
+
+    lua.clear_instruction_limit();
+    lua.do_string("for i = 1, 200000 do end", None).unwrap();
+
+    // the limit is larger than the max count of the hook
+    lua.set_instruction_limit(u32::MAX as u64 + 10).unwrap();
+    lua.do_string("for i = 1, 1000 do end", None).unwrap();
+    lua.clear_instruction_limit();
+}
+
+#[test]
+fn call_with_budget() {
+    let lua = Lua::with_open_libs();
+    let counter = Rc::new(RefCell::new(0));
+    let counter2 = counter.clone();
+    lua.on_line(move |_, _, _| {
+        *counter2.borrow_mut() += 1;
+        HookAction::Continue
+    })
+    .unwrap();
+
+    let spin = lua
+        .load("local n = ... for i = 1, n do end return n", None)
+        .unwrap();
+    let budget = Budget {
+        instructions: Some(10000),
+        memory: None,
+    };
+    let err = lua
+        .call_with_budget::<i64>(spin.clone(), 1000000, budget)
+        .unwrap_err();
+    assert!(format!("{err:?}").contains("instruction budget exceeded"));
+    assert_eq!(
+        lua.call_with_budget::<i64>(spin.clone(), 10, budget)
+            .unwrap(),
+        10
+    );
+
+    let alloc = lua
+        .load(
+            "local t = {} for i = 1, ... do t[i] = ('x'):rep(100) .. i end",
+            None,
+        )
+        .unwrap();
+    let budget = Budget {
+        instructions: None,
+        memory: Some(64 * 1024),
+    };
+    lua.call_with_budget::<()>(alloc.clone(), 100000, budget)
+        .unwrap_err();
+    lua.call_with_budget::<()>(alloc.clone(), 10, budget)
+        .unwrap();
+
+    // the previous hook and allocator are restored
+    *counter.borrow_mut() = 0;
+    assert_eq!(spin.pcall::<_, i64>(1000000).unwrap(), 1000000);
+    alloc.pcall_void(100000).unwrap();
+    assert!(*counter.borrow() > 0);
+}
+
+#[test]
+fn budget_with_gc_observer() {
+    let lua = Lua::with_open_libs();
+    let used = Rc::new(Cell::new(0));
+    let alloc = lua
+        .load(
+            "local t = {} for i = 1, ... do t[i] = ('x'):rep(100) .. i end",
+            None,
+        )
+        .unwrap();
+    let budget = Budget {
+        instructions: None,
+        memory: Some(64 * 1024),
+    };
+
+    // the observer installed inside the budget outlives the limiter
+    let used2 = used.clone();
+    let observe = lua
+        .new_closure(move |lua: &LuaState| {
+            let used = used2.clone();
+            lua.set_gc_observer(move |_, n| used.set(n))
+        })
+        .unwrap();
+    lua.call_with_budget::<()>(observe, (), budget).unwrap();
+    lua.call_with_budget::<()>(alloc.clone(), 100000, budget)
+        .unwrap_err();
+    lua.gc_collect().unwrap();
+    lua.gc_step().unwrap();
+    assert!(used.get().abs_diff(lua.used_memory()) < 1024);
+
+    // the limiter installed over the observer is removed without detaching it
+    used.set(0);
+    lua.call_with_budget::<()>(alloc.clone(), 100000, budget)
+        .unwrap_err();
+    alloc.pcall_void(100000).unwrap();
+    lua.gc_collect().unwrap();
+    lua.gc_step().unwrap();
+    assert!(used.get() > 0);
+    assert!(used.get().abs_diff(lua.used_memory()) < 1024);
+
+    lua.remove_gc_observer();
+    lua.gc_collect().unwrap();
+    alloc.pcall_void(100000).unwrap();
+}
+
+#[test]
+fn scope_buffer() {
+    let lua = Lua::with_open_libs();
+    let mut buf = [0u8; 8];
+    lua.scope(|scope| {
+        let udata = scope.create_buffer(&mut buf)?;
+        lua.global().set("buf", udata)?;
+        lua.do_string(
+            r#"
+            assert(#buf == 8 and buf[1] == 0)
+            for i = 1, 3 do buf[i] = i end
+            assert(buf:write(7, 'xyz') == 2)
+            assert(not pcall(function() buf[9] = 1 end))
+            "#,
+            None,
+        )
+    })
+    .unwrap();
+    assert_eq!(buf, [1, 2, 3, 0, 0, 0, b'x', b'y']);
+    lua.do_string("return buf[1]", None).unwrap_err();
+}
+
+#[test]
+fn load_with_mode() {
+    let lua = Lua::with_open_libs();
+    let bytecode = lua.load("return 1", None).unwrap().dump(false);
+
+    let err = lua
+        .load_with_mode(&bytecode, None, LoadMode::Text)
+        .unwrap_err();
+    assert!(matches!(err, LuaError::Syntax(_)), "{err:?}");
+    let func = lua
+        .load_with_mode(&bytecode, Some("bin"), LoadMode::TextAndBinary)
+        .unwrap();
+    assert_eq!(func.pcall::<_, i32>(()).unwrap(), 1);
+
+    let func = lua
+        .load_with_mode("return 2", None, LoadMode::Text)
+        .unwrap();
+    assert_eq!(func.pcall::<_, i32>(()).unwrap(), 2);
+    lua.load_with_mode("return 2", None, LoadMode::Binary)
+        .unwrap_err();
+}
+
+#[test]
+fn load_with_env() {
+    let lua = Lua::with_open_libs();
+    lua.global().set("secret", 42).unwrap();
+    let env = lua.new_table().unwrap();
+    env.set("x", 1).unwrap();
+    let func = lua
+        .load_with_env("y = x + 1 return secret, print", None, env.clone())
+        .unwrap();
+    let (secret, print): (Option<i32>, LuaValue) = func.pcall(()).unwrap();
+    assert_eq!(secret, None);
+    assert!(matches!(print, LuaValue::Nil));
+    assert_eq!(env.get("y").unwrap().to_integer(), 2);
+    assert!(lua.global().get("y").unwrap().is_nil());
+
+    let bytecode = lua.load("return x", None).unwrap().dump(true);
+    lua.load_with_env(&bytecode, None, env).unwrap_err();
+}
+
+#[test]
+fn function_environment() {
+    let lua = Lua::with_open_libs();
+    let func = lua.load("return value", None).unwrap();
+    assert!(func
+        .environment()
+        .unwrap()
+        .unwrap()
+        .raw_equal(&lua.global()));
+
+    let env = lua.new_table().unwrap();
+    env.set("value", 3).unwrap();
+    func.set_environment(env.clone()).unwrap();
+    assert_eq!(func.pcall::<_, i32>(()).unwrap(), 3);
+    assert!(func.environment().unwrap().unwrap().raw_equal(&env));
+
+    // the _ENV of a nested function is not the first upvalue
+    let inner: LuaFunction = lua
+        .exec("local a = 1 return function() return a, value end", None)
+        .unwrap();
+    inner.set_environment(env).unwrap();
+    assert_eq!(inner.pcall::<_, (i32, i32)>(()).unwrap(), (1, 3));
+
+    let print: LuaFunction = lua.global().get("print").unwrap().try_into().unwrap();
+    assert!(print.environment().unwrap().is_none());
+    print.set_environment(lua.new_table().unwrap()).unwrap_err();
+}
+
+#[test]
+fn load_all_chunks() {
+    let lua = Lua::with_open_libs();
+    let results = lua.load_all([("good", "return 1"), ("bad", "return +"), ("last", "")]);
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[0].0, "good");
+    assert_eq!(
+        results[0].1.as_ref().unwrap().pcall::<_, i32>(()).unwrap(),
+        1
+    );
+    assert_eq!(results[1].0, "bad");
+    assert!(matches!(results[1].1, Err(LuaError::Syntax(_))));
+    assert!(results[2].1.is_ok());
+}
+
+#[test]
+fn exec_returning() {
+    let lua = Lua::with_open_libs();
+    assert_eq!(lua.exec::<i32>("local x=5; return x*2", None).unwrap(), 10);
+    let (a, b): (i32, String) = lua.exec("return 1, 'b'", Some("multi")).unwrap();
+    assert_eq!((a, b.as_str()), (1, "b"));
+    lua.exec::<()>("local x = 1", None).unwrap();
+    lua.exec::<()>("error 'oops'", None).unwrap_err();
+}
+
+#[test]
+fn eval_cached() {
+    let lua = Lua::with_open_libs();
+    // the chunk returns itself, which is the same function if not compiled again
+    let src = "n = (n or 0) + 1 return debug.getinfo(1, 'f').func";
+    let first: LuaFunction = lua.eval_cached(src).unwrap();
+    for _ in 0..10 {
+        let chunk: LuaFunction = lua.eval_cached(src).unwrap();
+        assert!(chunk.raw_equal(&first));
+    }
+    assert_eq!(lua.global().get("n").unwrap().to_integer(), 11);
+
+    lua.clear_eval_cache().unwrap();
+    let chunk: LuaFunction = lua.eval_cached(src).unwrap();
+    assert!(!chunk.raw_equal(&first));
+    assert_eq!(lua.eval_cached::<i32>("return 1 + 1").unwrap(), 2);
+
+    // the cache is bounded, the chunks are compiled again after it's full
+    let chunk: LuaFunction = lua.eval_cached(src).unwrap();
+    for i in 0..1000 {
+        assert_eq!(lua.eval_cached::<i32>(&format!("return {i}")).unwrap(), i);
+    }
+    let again: LuaFunction = lua.eval_cached(src).unwrap();
+    assert!(!again.raw_equal(&chunk));
+    let cached: LuaFunction = lua.eval_cached(src).unwrap();
+    assert!(cached.raw_equal(&again));
+}
+
+#[test]
+fn chainable_methods() {
+    #[derive(Default)]
+    struct Builder {
+        parts: Vec<String>,
+    }
+
+    impl UserData for Builder {
+        type Trans = RefCell<Self>;
+
+        fn methods(mt: UserdataRegistry<Self>) -> LuaResult<()> {
+            mt.add_mut("push", |this: &mut Self, part: String| {
+                this.parts.push(part);
+                Chainable
+            })?;
+            mt.add_mut("clear", |this: &mut Self| {
+                this.parts.clear();
+                Chainable
+            })?;
+            mt.add("build", |this: &Self| this.parts.join(","))?;
+            Ok(())
+        }
+    }
+
+    let lua = Lua::with_open_libs();
+    lua.global().set("builder", Builder::default()).unwrap();
+    lua.do_string(
+        "assert(builder:push('x'):clear():push('a'):push('b'):build() == 'a,b')",
+        None,
+    )
+    .unwrap();
+}
+
+#[test]
+fn weak_registry_value() {
+    let lua = Lua::with_open_libs();
+
+    let weak = {
+        let t = lua.new_table().unwrap();
+        lua.global().set("strong", t.clone()).unwrap();
+        lua.weak_registry_value(t).unwrap()
+    };
+    lua.gc_collect().unwrap();
+    assert_eq!(weak.upgrade(&lua).unwrap().type_of(), LuaType::Table);
+
+    lua.global().set("strong", ()).unwrap();
+    lua.gc_collect().unwrap();
+    assert!(weak.upgrade(&lua).is_none());
+}
+
+#[test]
+fn load_binary_verified() {
+    let lua = Lua::with_open_libs();
+
+    let bytecode = lua.load("return 1 + 2", None).unwrap().dump(false);
+    assert!(lua.load_binary_verified(&bytecode).is_err());
+
+    lua.set_bytecode_trusted(true).unwrap();
+    let fun = lua.load_binary_verified(&bytecode).unwrap();
+    assert_eq!(fun.pcall::<_, i32>(()).unwrap(), 3);
+    assert!(lua.load_binary_verified(b"return 1").is_err());
+    assert!(lua.load_binary_verified(&bytecode[..20]).is_err());
+
+    lua.set_bytecode_trusted(false).unwrap();
+    assert!(lua.load_binary_verified(&bytecode).is_err());
+}
+
+#[test]
+fn max_string_length() {
+    let lua = Lua::with_open_libs();
+    lua.set_max_string_length(16).unwrap();
+    let eval = |code: &str| lua.load(code, None).unwrap().pcall::<_, String>(());
+
+    assert_eq!(eval("return string.rep('ab', 3, ',')").unwrap(), "ab,ab,ab");
+    assert_eq!(eval("return ('x'):rep(16)").unwrap(), "xxxxxxxxxxxxxxxx");
+    assert!(eval("return ('x'):rep(17)").is_err());
+    assert!(eval("return string.rep('x', 1e9)").is_err());
+    assert!(eval("return string.rep('x', math.maxinteger)").is_err());
+
+    lua.set_max_string_length(32).unwrap();
+    assert_eq!(eval("return string.rep('x', 32)").unwrap().len(), 32);
+}
+
+#[test]
+fn presence_of_return() {
+    let lua = Lua::with_open_libs();
+    let call = |code: &str| {
+        lua.load(code, None)
+            .unwrap()
+            .pcall::<_, Presence<Option<i32>>>(())
+            .unwrap()
+    };
+
+    assert_eq!(call("return"), Presence::Absent);
+    assert_eq!(call("return nil"), Presence::Present(None));
+    assert_eq!(call("return 1"), Presence::Present(Some(1)));
+
+    let fun = lua
+        .new_function(|_, a: Presence<Option<i32>>| match a {
+            Presence::Absent => "absent",
+            Presence::Present(None) => "nil",
+            Presence::Present(Some(_)) => "value",
+        })
+        .unwrap();
+    lua.global().set("fun", fun).unwrap();
+    lua.do_string("assert(fun() == 'absent')", None).unwrap();
+    lua.do_string("assert(fun(nil) == 'nil')", None).unwrap();
+    lua.do_string("assert(fun(1) == 'value')", None).unwrap();
+}
+
+#[test]
+fn compare_values() {
+    let lua = Lua::with_open_libs();
+    let vals = lua
+        .load(
+            r#"
+            local mt = {
+                __lt = function(a, b) return a.v < b.v end,
+                __le = function(a, b) return a.v <= b.v end,
+            }
+            local function new(v) return setmetatable({v = v}, mt) end
+            return new(3), new(1), new(2), {}
+            "#,
+            None,
+        )
+        .unwrap()
+        .pcall::<_, (ValRef, ValRef, ValRef, ValRef)>(())
+        .unwrap();
+    let bad = vals.3;
+    let mut vals = vec![vals.0, vals.1, vals.2];
+
+    assert!(vals[1].lt(&vals[0]).unwrap());
+    assert!(vals[1].le(&vals[1]).unwrap());
+    assert!(!vals[0].compare(&vals[2], Comparison::Le).unwrap());
+    assert!(lua
+        .new_val(1)
+        .unwrap()
+        .lt(&lua.new_val(2.5).unwrap())
+        .unwrap());
+
+    vals.sort_by(|a, b| {
+        if a.lt(b).unwrap() {
+            core::cmp::Ordering::Less
+        } else {
+            core::cmp::Ordering::Greater
+        }
+    });
+    let sorted = vals
+        .iter()
+        .map(|v| v.get("v").unwrap().cast::<i32>().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(sorted, [1, 2, 3]);
+
+    let (a, one) = (lua.new_val("a").unwrap(), lua.new_val(1).unwrap());
+    let top = lua.stack_top();
+    assert!(bad.lt(&vals[0]).is_err());
+    assert!(a.le(&one).is_err());
+    assert_eq!(lua.stack_top(), top);
+}
+
+#[test]
+fn light_userdata_pointer() {
+    let lua = Lua::with_open_libs();
+    let mut data = 42u64;
+
+    let p = lua
+        .new_val(LuaValue::light_userdata(&mut data as *mut u64))
+        .unwrap();
+    let ptr = p.as_light_userdata::<u64>().unwrap();
+    assert_eq!(ptr, &mut data as *mut u64);
+    unsafe { *ptr += 1 };
+    assert_eq!(data, 43);
+
+    assert!(lua.new_val(1).unwrap().as_light_userdata::<u64>().is_none());
+    assert!(lua
+        .new_val(LuaValue::light_userdata(core::ptr::null::<u64>()))
+        .unwrap()
+        .as_light_userdata::<u64>()
+        .unwrap()
+        .is_null());
+}
+
+#[test]
+fn error_traceback() {
+    let lua = Lua::with_open_libs();
+    let err = lua
+        .do_string("local function f() error('oops') end f()", Some("=script"))
+        .unwrap_err();
+    assert_eq!(
+        err.traceback().map(|tb| tb.starts_with("stack traceback:")),
+        Some(true)
+    );
+    assert!(err.traceback().unwrap().contains("in local 'f'"));
+    // the traceback is still a part of the runtime error message
+    match &err {
+        LuaError::Runtime(msg) => {
+            assert_eq!(
+                *msg,
+                format!("script:1: oops\n{}", err.traceback().unwrap())
+            );
+        }
+        err => panic!("{err:?}"),
+    }
+
+    lua.set_capture_traceback(false).unwrap();
+    let err = lua.do_string("error('oops')", None).unwrap_err();
+    assert!(err.traceback().is_none());
+}
+
+#[test]
+fn last_error() {
+    let lua = Lua::with_open_libs();
+    assert!(lua.last_error().is_none());
+
+    let _ = lua.do_string("error('first')", None);
+    let _ = lua.do_string("error('second')", None);
+    let err = lua.last_error().unwrap();
+    assert!(matches!(err, LuaError::Runtime(ref message) if message.contains("second")));
+    assert!(lua.last_error().is_none());
+
+    lua.set_keep_last_error(true);
+    let _ = lua.do_string("error('third')", None);
+    lua.do_string("return 1", None).unwrap();
+    assert!(format!("{:?}", lua.last_error().unwrap()).contains("third"));
+    assert!(format!("{:?}", lua.last_error().unwrap()).contains("third"));
+}
+
+#[test]
+fn table_sort() {
+    let lua = Lua::with_open_libs();
+
+    let t = lua.new_val(vec![3, 1, 4, 1, 5, 9, 2, 6]).unwrap();
+    let t: LuaTable = t.try_into().unwrap();
+    t.sort(None).unwrap();
+    assert_eq!(t.cast::<Vec<i32>>().unwrap(), [1, 1, 2, 3, 4, 5, 6, 9]);
+
+    let desc = lua
+        .load("return function(a, b) return a > b end", None)
+        .unwrap();
+    let desc: LuaFunction = desc.pcall(()).unwrap();
+    t.sort(Some(desc)).unwrap();
+    assert_eq!(t.cast::<Vec<i32>>().unwrap(), [9, 6, 5, 4, 3, 2, 1, 1]);
+
+    #[derive(Default)]
+    struct Version(u32, u32);
+
+    impl UserData for Version {
+        fn getter(fields: UserdataRegistry<Self>) -> LuaResult<()> {
+            fields.add("major", |this: &Self| this.0)?;
+            Ok(())
+        }
+
+        fn metatable(mt: UserdataRegistry<Self>) -> LuaResult<()> {
+            mt.add("__lt", |a: &Self, b: &Self| (a.0, a.1) < (b.0, b.1))?;
+            Ok(())
+        }
+    }
+
+    let versions = lua
+        .new_val(vec![
+            Version(2, 0),
+            Version(1, 5),
+            Version(1, 2),
+            Version(3, 1),
+        ])
+        .unwrap();
+    let versions: LuaTable = versions.try_into().unwrap();
+    versions.sort(None).unwrap();
+    let majors = (1..=4)
+        .map(|i| {
+            versions
+                .raw_geti(i)
+                .unwrap()
+                .get("major")
+                .unwrap()
+                .cast::<u32>()
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(majors, [1, 1, 2, 3]);
+
+    let mixed = lua.new_val(vec![lua.new_val(1).unwrap(), lua.new_val("a").unwrap()]);
+    let mixed: LuaTable = mixed.unwrap().try_into().unwrap();
+    assert!(mixed.sort(None).is_err());
+    let bad = lua
+        .load("return function(a, b) error('bad') end", None)
+        .unwrap();
+    assert!(t.sort(Some(bad.pcall(()).unwrap())).is_err());
+    assert_eq!(t.cast::<Vec<i32>>().unwrap(), [9, 6, 5, 4, 3, 2, 1, 1]);
+
+    // more elements than the stack can hold
+    let large: LuaTable = lua
+        .load(
+            "local t = {} for i = 1, 1200000 do t[i] = i end t[1], t[2] = 2, 1 return t",
+            None,
+        )
+        .unwrap()
+        .pcall(())
+        .unwrap();
+    large.sort(None).unwrap();
+    assert_eq!(large.read_range::<i64>(1, 3).unwrap(), [1, 2, 3]);
+    assert_eq!(large.raw_geti(1200000).unwrap().to_integer(), 1200000);
+}
+
+#[test]
+fn table_remove() {
+    let lua = Lua::with_open_libs();
+    let t: LuaTable = lua.new_val(vec![1, 2, 3, 4]).unwrap().try_into().unwrap();
+
+    assert_eq!(t.remove(2).unwrap().cast::<i32>().unwrap(), 2);
+    assert_eq!(t.cast::<Vec<i32>>().unwrap(), [1, 3, 4]);
+    assert_eq!(t.remove(3).unwrap().cast::<i32>().unwrap(), 4);
+    assert_eq!(t.remove(1).unwrap().cast::<i32>().unwrap(), 1);
+    assert_eq!(t.cast::<Vec<i32>>().unwrap(), [3]);
+
+    assert!(t.remove(0).unwrap().is_nil());
+    assert!(t.remove(2).unwrap().is_nil());
+    assert_eq!(t.raw_len(), 1);
+
+    // used as a queue
+    t.push(5).unwrap();
+    t.push(6).unwrap();
+    let mut items = vec![];
+    while t.raw_len() > 0 {
+        items.push(t.remove(1).unwrap().cast::<i32>().unwrap());
+    }
+    assert_eq!(items, [3, 5, 6]);
+}
+
+#[test]
+fn register_enum() {
+    let lua = Lua::with_open_libs();
+    lua.register_enum("Color", &[("Red", 0), ("Green", 1), ("Blue", 2)])
+        .unwrap();
+
+    lua.do_string(
+        r#"
+        assert(Color.Red == 0 and Color.Blue == 2)
+        assert(Color.Green == Color.Green and Color.Red ~= Color.Green)
+        assert(Color[1] == 'Green')
+        assert(Color.Purple == nil)
+        assert(getmetatable(Color) == false)
+        assert(not pcall(function() Color.Red = 10 end))
+        assert(not pcall(setmetatable, Color, {}))
+        assert(Color.Red == 0)
+        "#,
+        None,
+    )
+    .unwrap();
+
+    let err = lua.do_string("Color.Black = 3", None).unwrap_err();
+    assert!(format!("{err:?}").contains("enum Color is read-only"));
+}
+
+#[test]
+fn table_clear() {
+    let lua = Lua::with_open_libs();
+    let t: LuaTable = lua.new_val(vec![1, 2, 3]).unwrap().try_into().unwrap();
+    for i in 0..100 {
+        t.set(format!("key{i}"), i).unwrap();
+    }
+    let mt = lua.new_table().unwrap();
+    mt.set(
+        "__newindex",
+        lua.load("return function() error('newindex') end", None)
+            .unwrap()
+            .pcall::<_, LuaFunction>(())
+            .unwrap(),
+    )
+    .unwrap();
+    t.set_metatable(mt).unwrap();
+
+    let top = lua.stack_top();
+    t.clear().unwrap();
+    assert_eq!(lua.stack_top(), top);
+    assert_eq!(t.entry_count(), 0);
+    assert_eq!(t.raw_len(), 0);
+    assert!(t.metatable().unwrap().is_some());
+
+    t.raw_set("reused", true).unwrap();
+    assert_eq!(t.entry_count(), 1);
+}
+
+#[test]
+fn native_function() {
+    let lua = Lua::with_open_libs();
+
+    let print: LuaFunction = lua.global().get("print").unwrap().try_into().unwrap();
+    assert!(print.is_native());
+    assert!(!print.is_lua());
+
+    let closure = lua.new_function(|_, ()| 1).unwrap();
+    assert!(closure.is_native());
+
+    let fun = lua.load("return function() end", None).unwrap();
+    assert!(fun.is_lua());
+    let fun: LuaFunction = fun.pcall(()).unwrap();
+    assert!(fun.is_lua());
+    assert!(!fun.is_native());
+}
+
+#[test]
+fn fixed_size_array() {
+    let lua = Lua::with_open_libs();
+
+    let v = lua.new_val([1.0, 2.5, 3.0]).unwrap();
+    assert_eq!(v.cast::<Vec<f64>>().unwrap(), [1.0, 2.5, 3.0]);
+    assert_eq!(v.cast::<[f64; 3]>().unwrap(), [1.0, 2.5, 3.0]);
+
+    let short = lua.new_val([1.0, 2.0]).unwrap();
+    let err = short.cast::<[f64; 3]>().unwrap_err();
+    assert!(format!("{err:?}").contains("expect an array of length 3, but got 2"));
+    let long = lua.new_val([1.0, 2.0, 3.0, 4.0]).unwrap();
+    assert!(long.cast::<[f64; 3]>().is_err());
+    assert!(lua.new_val(1).unwrap().cast::<[f64; 3]>().is_err());
+
+    let length = lua
+        .new_function(|_, [x, y, z]: [f64; 3]| (x * x + y * y + z * z).sqrt())
+        .unwrap();
+    assert_eq!(length.pcall::<_, f64>([2.0, 3.0, 6.0]).unwrap(), 7.0);
+    assert!(length.pcall::<_, f64>([2.0, 3.0]).is_err());
+}
+
+#[test]
+fn ordered_maps_and_sets() {
+    use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+    let lua = Lua::with_open_libs();
+    let map = BTreeMap::from([("b".to_string(), 2), ("a".to_string(), 1)]);
+    let val = lua.new_val(map.clone()).unwrap();
+    assert_eq!(val.get("a").unwrap().cast::<i32>().unwrap(), 1);
+    assert_eq!(val.cast_into::<BTreeMap<String, i32>>().unwrap(), map);
+
+    let set = BTreeSet::from([3, 1, 2]);
+    let val = lua.new_val(set.clone()).unwrap();
+    assert!(val.get(2).unwrap().to_bool());
+    assert_eq!(val.cast::<BTreeSet<i32>>().unwrap(), set);
+    assert_eq!(
+        val.cast::<HashSet<i32>>().unwrap(),
+        set.iter().copied().collect()
+    );
+
+    let val = lua
+        .load("return {x = true, y = false, z = 1}", None)
+        .unwrap()
+        .pcall::<_, ValRef>(())
+        .unwrap();
+    assert_eq!(
+        val.cast::<BTreeSet<String>>().unwrap(),
+        BTreeSet::from(["x".to_string(), "z".to_string()])
+    );
+    let val = lua.new_val(HashSet::from(["k"])).unwrap();
+    assert_eq!(
+        val.cast::<HashSet<String>>().unwrap(),
+        HashSet::from(["k".to_string()])
+    );
+}
+
+#[test]
+fn require_whitelist() {
+    let lua = Lua::with_open_libs();
+    lua.do_string(
+        r#"
+        package.preload.allowed_mod = function() return {} end
+        package.preload.secret_mod = function() return {} end
+        "#,
+        None,
+    )
+    .unwrap();
+    lua.set_require_whitelist(&["allowed_mod"]).unwrap();
+
+    lua.do_string("assert(type(require 'allowed_mod') == 'table')", None)
+        .unwrap();
+    let err = lua.do_string("require 'secret_mod'", None).unwrap_err();
+    assert!(format!("{err:?}").contains("module 'secret_mod' is not allowed"));
+    // the searchers after the whitelist still work
+    let err = lua.do_string("require 'missing_mod'", None).unwrap_err();
+    assert!(format!("{err:?}").contains("not allowed"));
+
+    lua.set_require_whitelist(&["allowed_mod", "missing_mod"])
+        .unwrap();
+    let err = lua.do_string("require 'missing_mod'", None).unwrap_err();
+    assert!(format!("{err:?}").contains("module 'missing_mod' not found"));
+    lua.do_string("assert(#package.searchers == 5)", None)
+        .unwrap();
+}
+
+#[test]
+fn custom_searcher() {
+    use std::collections::HashMap;
+
+    let lua = Lua::with_open_libs();
+    let files = HashMap::from([("vfs.greet", "return { hello = function() return 'hi' end }")]);
+    lua.add_searcher(move |s, name| {
+        files
+            .get(name)
+            .map(|code| s.load(code, Some(name)))
+            .transpose()
+    })
+    .unwrap();
+
+    lua.do_string("assert(require('vfs.greet').hello() == 'hi')", None)
+        .unwrap();
+    let err = lua.do_string("require 'vfs.missing'", None).unwrap_err();
+    assert!(format!("{err:?}").contains("module 'vfs.missing' not found"));
+}
+
+#[test]
+fn system_time_round_trip() {
+    use ezlua::binding::std::time::UnixNanos;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    let lua = Lua::with_open_libs();
+    let now = SystemTime::now();
+    let back = lua.new_val(now).unwrap().cast::<SystemTime>().unwrap();
+    let diff = back
+        .duration_since(now)
+        .unwrap_or_else(|err| err.duration());
+    assert!(diff < Duration::from_micros(1));
+
+    let before_epoch = lua
+        .load("return -1.5", None)
+        .unwrap()
+        .pcall::<_, SystemTime>(())
+        .unwrap();
+    assert_eq!(before_epoch, UNIX_EPOCH - Duration::from_millis(1500));
+    assert!(lua.new_val("now").unwrap().cast::<SystemTime>().is_err());
+
+    let nanos = lua.new_val(UnixNanos(now)).unwrap();
+    assert_eq!(nanos.type_of(), LuaType::Number);
+    assert_eq!(nanos.cast::<UnixNanos>().unwrap(), UnixNanos(now));
+
+    // out of the range of SystemTime
+    let err = lua.new_val(1e19).unwrap().cast::<SystemTime>().unwrap_err();
+    assert!(matches!(err, LuaError::Convert(_)), "{err:?}");
+
+    // the time before the epoch is negative
+    let before = UNIX_EPOCH - Duration::from_nanos(1_500_000_001);
+    let nanos = lua.new_val(UnixNanos(before)).unwrap();
+    assert_eq!(nanos.to_integer(), -1_500_000_001);
+    assert_eq!(nanos.cast::<UnixNanos>().unwrap(), UnixNanos(before));
+    let secs = lua.new_val(before).unwrap();
+    assert!((secs.to_number() + 1.500000001).abs() < 1e-9);
+}
@@ -110,3 +110,172 @@ fn test() {
             .unwrap();
     }
 }
+
+#[test]
+fn scalar_values() {
+    use ezlua::prelude::*;
+    use std::time::Instant;
+
+    let lua = Lua::with_open_libs();
+    let top = lua.stack_top();
+    let free_slots = lua.free_slots().len();
+
+    let now = Instant::now();
+    let values = (0..100000)
+        .map(|i| lua.new_value(i).unwrap())
+        .chain((0..100000).map(|i| lua.new_value(i as f64 * 0.5).unwrap()))
+        .chain((0..100000).map(|i| lua.new_value(i % 2 == 0).unwrap()))
+        .collect::<Vec<_>>();
+    println!("[new_value] scalars: {:?}", now.elapsed());
+
+    // scalar values never occupy the stack slots
+    assert_eq!(lua.stack_top(), top);
+    assert_eq!(lua.free_slots().len(), free_slots);
+    assert_eq!(values.len(), 300000);
+
+    let now = Instant::now();
+    let refs = (0..100000)
+        .map(|i| lua.new_val(i).unwrap())
+        .collect::<Vec<_>>();
+    println!("[new_val] integers: {:?}", now.elapsed());
+    assert!(lua.stack_top() > top + free_slots as i32);
+    drop(refs);
+}
+
+#[test]
+fn table_read_range() {
+    use ezlua::prelude::*;
+    use std::time::Instant;
+
+    let lua = Lua::with_open_libs();
+    let t = lua
+        .new_val((0..100000).collect::<Vec<i64>>())
+        .unwrap()
+        .cast_into::<LuaTable>()
+        .unwrap();
+
+    let now = Instant::now();
+    let items = t.cast::<Vec<i64>>().unwrap();
+    println!("[Vec::from_lua] {:?}", now.elapsed());
+
+    let now = Instant::now();
+    let range = t.read_range::<i64>(1, t.raw_len() as _).unwrap();
+    println!("[Table::read_range] {:?}", now.elapsed());
+
+    assert_eq!(items, range);
+}
+
+#[test]
+fn serialize_arrays() {
+    use ezlua::prelude::*;
+    use std::time::Instant;
+
+    let lua = Lua::with_open_libs();
+    let arrays = lua.new_table().unwrap();
+    for i in 0..10000 {
+        let array = lua.new_array_table(0).unwrap();
+        array.push(i).unwrap();
+        arrays.push(array).unwrap();
+    }
+    assert!(lua.is_array_metatable(&lua.array_metatable().unwrap()));
+    assert!(!lua.is_array_metatable(&lua.new_table().unwrap()));
+
+    let now = Instant::now();
+    let json = serde_json::to_string(&*arrays).unwrap();
+    println!("[serialize] arrays: {:?}", now.elapsed());
+    assert!(json.starts_with("[[0],[1],"));
+
+    let now = Instant::now();
+    let values = arrays.deserialize::<Vec<Vec<i32>>>().unwrap();
+    println!("[deserialize] arrays: {:?}", now.elapsed());
+    assert_eq!(values.len(), 10000);
+}
+
+#[test]
+fn table_extend() {
+    use ezlua::prelude::*;
+    use std::time::Instant;
+
+    let lua = Lua::with_open_libs();
+
+    let pushed = lua.new_table().unwrap();
+    let now = Instant::now();
+    for i in 0..10000 {
+        pushed.push(i).unwrap();
+    }
+    println!("[Table::push] {:?}", now.elapsed());
+
+    let extended = lua.new_table().unwrap();
+    extended.push(-1).unwrap();
+    let now = Instant::now();
+    extended.extend(0..10000).unwrap();
+    println!("[Table::extend] {:?}", now.elapsed());
+
+    assert_eq!(extended.raw_len(), 10001);
+    assert_eq!(
+        extended.read_range::<i64>(2, 10001).unwrap(),
+        pushed.cast::<Vec<i64>>().unwrap()
+    );
+}
+
+#[test]
+fn collect_numbers() {
+    use ezlua::prelude::*;
+    use std::time::Instant;
+
+    let lua = Lua::with_open_libs();
+    let array = lua
+        .load(
+            "local t = {} for i = 1, 10000 do t[i] = i * 0.5 end return t",
+            None,
+        )
+        .unwrap()
+        .pcall::<_, LuaTable>(())
+        .unwrap();
+
+    let now = Instant::now();
+    let generic = array.cast::<Vec<f64>>().unwrap();
+    println!("[Vec<f64>::from_lua] {:?}", now.elapsed());
+    let now = Instant::now();
+    let numbers = array.collect_numbers().unwrap();
+    println!("[Table::collect_numbers] {:?}", now.elapsed());
+    assert_eq!(numbers, generic);
+
+    assert!(array.collect_integers().is_err());
+    let short = lua.exec::<LuaTable>("return {1, 2.0, 3}", None).unwrap();
+    assert_eq!(short.collect_integers().unwrap(), [1, 2, 3]);
+    let mixed = lua.exec::<LuaTable>("return {1, 'x'}", None).unwrap();
+    assert!(mixed.collect_numbers().is_err());
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn json_transcode() {
+    use ezlua::prelude::*;
+    use std::time::Instant;
+
+    let items = (0..10000)
+        .map(|i| format!(r#"{{"id":{i},"name":"item{i}","tags":["a","b"]}}"#))
+        .collect::<Vec<_>>();
+    let text = format!("[{}]", items.join(","));
+    let lua = Lua::with_open_libs();
+
+    let now = Instant::now();
+    let direct = lua.from_json(text.as_bytes()).unwrap();
+    let encoded = direct.to_json_vec().unwrap();
+    println!("[json] direct: {:?}", now.elapsed());
+
+    let now = Instant::now();
+    let value: serde_json::Value = serde_json::from_slice(text.as_bytes()).unwrap();
+    let twostep = lua.new_val(value).unwrap();
+    let value: serde_json::Value = twostep.deserialize().unwrap();
+    let twostep_encoded = serde_json::to_vec(&value).unwrap();
+    println!("[json] two-step: {:?}", now.elapsed());
+
+    assert_eq!(
+        serde_json::from_slice::<serde_json::Value>(&encoded).unwrap(),
+        serde_json::from_slice::<serde_json::Value>(&twostep_encoded).unwrap()
+    );
+    assert_eq!(LuaTable::try_from(direct).unwrap().raw_len(), 10000);
+    assert!(lua.from_json(b"{").is_err());
+}
@@ -23,3 +23,64 @@ binding_test!(stdlib, "tests/test_std.lua");
 
 #[cfg(feature = "json")]
 binding_test!(json, "tests/json.lua");
+
+#[cfg(feature = "msgpack")]
+binding_test!(msgpack, "tests/msgpack.lua");
+
+#[cfg(feature = "chrono")]
+#[test]
+fn chrono_timestamp() {
+    use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+    use ezlua::binding::chrono::Timestamp;
+
+    let s = Lua::with_open_libs();
+    let g = s.global();
+    let dt = Utc.timestamp_opt(1_700_000_000, 500_000_000).unwrap();
+    g.set("ts", Timestamp(dt)).unwrap();
+    s.do_string("assert(ts == 1700000000.5)", None).unwrap();
+    let Timestamp(back) = g
+        .get("ts")
+        .unwrap()
+        .cast::<Timestamp<DateTime<Utc>>>()
+        .unwrap();
+    assert_eq!(back, dt);
+
+    let naive = dt.naive_utc();
+    g.set("naive", Timestamp(naive)).unwrap();
+    let Timestamp(back) = g
+        .get("naive")
+        .unwrap()
+        .cast::<Timestamp<NaiveDateTime>>()
+        .unwrap();
+    assert_eq!(back, naive);
+
+    let cast = |v: ValRef| v.cast::<Timestamp<DateTime<Utc>>>().map(|t| t.0);
+    assert_eq!(cast(s.new_val(0).unwrap()).unwrap(), DateTime::UNIX_EPOCH);
+    assert_eq!(
+        cast(s.new_val("2023-11-14T22:13:20.5Z").unwrap()).unwrap(),
+        dt
+    );
+    assert_eq!(
+        cast(s.new_val("2023-11-15T06:13:20.5+08:00").unwrap()).unwrap(),
+        dt
+    );
+    assert_eq!(
+        cast(s.new_val("2023-11-14T22:13:20.5").unwrap()).unwrap(),
+        dt
+    );
+    assert!(cast(s.new_val("yesterday").unwrap()).is_err());
+    assert!(cast(s.new_val(true).unwrap()).is_err());
+
+    let chrono = ezlua::binding::chrono::open(&s).unwrap();
+    g.set("chrono", chrono).unwrap();
+    s.do_string(
+        r#"
+        assert(chrono.time_iso(1700000000.5) == '2023-11-14T22:13:20.500Z', chrono.time_iso(1700000000.5))
+        assert(chrono.time_iso('2023-11-14T22:13:20Z') == '2023-11-14T22:13:20Z')
+        local utc = chrono.DateTimeUtc.from_timestamp(1700000000)
+        assert(chrono.time_iso(utc) == '2023-11-14T22:13:20Z')
+        "#,
+        None,
+    )
+    .unwrap();
+}
@@ -22,4 +22,35 @@ for _, data in ipairs {
     local text = json.dump(data)
     print(text)
     assert(json.dump(json.load(text)) == text)
-end
\ No newline at end of file
+end
+local nested = {name = 'app', server = {port = 80, host = 'localhost'}, tags = {'a', 'b'}}
+assert(json.dump(nested, {sort_keys = true}) ==
+    '{"name":"app","server":{"host":"localhost","port":80},"tags":["a","b"]}')
+assert(json.encode(nested, {pretty = true, sort_keys = true}) == [[
+{
+  "name": "app",
+  "server": {
+    "host": "localhost",
+    "port": 80
+  },
+  "tags": [
+    "a",
+    "b"
+  ]
+}]])
+assert(json.encode({server = {port = 80}}, {indent = 4}) == [[
+{
+    "server": {
+        "port": 80
+    }
+}]])
+
+-- load keeps the nulls as nil, while decode keeps them as json.null
+assert(next(json.load('{"a":null}')) == nil)
+assert(json.load('[1,null,3]')[2] == nil)
+local obj = json.decode('{"a":null}')
+assert(obj.a == json.null and next(obj) == 'a')
+assert(json.encode(obj) == '{"a":null}')
+local arr = json.decode('[1,null,3]')
+assert(#arr == 3 and arr[2] == json.null)
+assert(json.encode(arr) == '[1,null,3]')
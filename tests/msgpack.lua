@@ -0,0 +1,43 @@
+local msgpack = require 'msgpack'
+
+local function equal(a, b)
+    if type(a) ~= 'table' or type(b) ~= 'table' then return a == b end
+    for k, v in pairs(a) do
+        if not equal(v, b[k]) then return false end
+    end
+    for k in pairs(b) do
+        if a[k] == nil then return false end
+    end
+    return true
+end
+
+local nested = {
+    name = 'app',
+    version = 3,
+    ratio = 0.25,
+    enabled = true,
+    servers = {
+        {host = 'a.example', ports = {80, 443}},
+        {host = 'b.example', ports = {8080}},
+    },
+}
+local encoded = msgpack.encode(nested)
+assert(type(encoded) == 'string')
+assert(equal(msgpack.decode(encoded), nested))
+
+-- array is encoded as msgpack array: fixarray of 3 positive fixints
+assert(msgpack.encode({1, 2, 3}) == '\x93\x01\x02\x03')
+-- map is encoded as msgpack map
+assert(msgpack.encode({a = 1}) == '\x81\xa1a\x01')
+
+-- binary data which is not valid utf8
+local blob = '\0\1\2\xff\xfe' .. string.rep('\x80', 300)
+local decoded = msgpack.decode(msgpack.encode({blob = blob}))
+assert(decoded.blob == blob)
+
+-- nil of msgpack is kept as msgpack.null
+local obj = msgpack.decode('\x82\xa1a\xc0\xa1b\x01')
+assert(obj.a == msgpack.null and obj.b == 1)
+assert(equal(msgpack.decode(msgpack.encode(obj)), obj))
+
+assert(not pcall(msgpack.decode, '\x93\x01'))
@@ -194,3 +194,163 @@ fn memory_leak() {
     .pcall_void(())
     .unwrap();
 }
+
+#[cfg(feature = "toml")]
+#[test]
+fn toml_value() {
+    let lua = Lua::with_open_libs();
+    let config: toml::Value = toml::from_str(
+        r#"
+        name = "ezlua"
+        created = 2024-01-02T03:04:05Z
+
+        [server]
+        ports = [8080, 8081]
+        ratio = 0.5
+
+        [server.tls]
+        enabled = true
+        "#,
+    )
+    .unwrap();
+    lua.global().set("config", config).unwrap();
+    lua.do_string(
+        r#"
+        assert(config.created == '2024-01-02T03:04:05Z')
+        assert(config.server.ports[2] == 8081 and config.server.tls.enabled)
+        config.server.ports[3] = 8082
+        config.name = config.name:upper()
+        "#,
+        None,
+    )
+    .unwrap();
+
+    let config = lua.global().get("config").unwrap().to_toml().unwrap();
+    assert_eq!(config["name"].as_str(), Some("EZLUA"));
+    assert_eq!(config["created"].as_str(), Some("2024-01-02T03:04:05Z"));
+    assert_eq!(
+        config["server"]["ports"].as_array().unwrap(),
+        &[8080.into(), 8081.into(), 8082.into()]
+    );
+    assert_eq!(config["server"]["ratio"].as_float(), Some(0.5));
+    assert_eq!(config["server"]["tls"]["enabled"].as_bool(), Some(true));
+}
+
+#[test]
+fn table_with_defaults() {
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Server {
+        host: String,
+        port: u16,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Config {
+        name: String,
+        debug: bool,
+        server: Server,
+    }
+
+    let lua = Lua::with_open_libs();
+    let defaults = lua
+        .load(
+            "return {name = 'app', debug = false, server = {host = 'localhost', port = 80}}",
+            None,
+        )
+        .unwrap()
+        .pcall::<_, LuaTable>(())
+        .unwrap();
+    let config = lua
+        .load("return {debug = true, server = {port = 8080}}", None)
+        .unwrap()
+        .pcall::<_, LuaTable>(())
+        .unwrap();
+
+    let merged = config.with_defaults(&defaults).unwrap();
+    assert_eq!(
+        merged.deserialize::<Config>().unwrap(),
+        Config {
+            name: "app".into(),
+            debug: true,
+            server: Server {
+                host: "localhost".into(),
+                port: 8080,
+            },
+        }
+    );
+    assert!(config.deserialize::<Config>().is_err());
+}
+
+#[test]
+fn wide_integer() {
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Wide {
+        small: i128,
+        large: u128,
+        negative: i128,
+    }
+
+    let lua = Lua::with_open_libs();
+    let wide = Wide {
+        small: 42,
+        large: u128::MAX,
+        negative: i128::MIN,
+    };
+    let val = lua.serialize_to_val(&wide).unwrap();
+    let t = LuaTable::try_from(val).unwrap();
+    assert!(t.get("small").unwrap().is_integer());
+    assert_eq!(
+        t.get("large").unwrap().to_str(),
+        Some("340282366920938463463374607431768211455")
+    );
+    assert_eq!(t.deserialize::<Wide>().unwrap(), wide);
+
+    // the same rules apply to ToLua and FromLua
+    let g = lua.global();
+    g.set("small", 7i128).unwrap();
+    g.set("large", u128::MAX).unwrap();
+    assert!(g.get("small").unwrap().is_integer());
+    assert_eq!(g.get("large").unwrap().cast::<u128>().unwrap(), u128::MAX);
+    assert_eq!(g.get("small").unwrap().cast::<u128>().unwrap(), 7);
+    assert!(g.get("large").unwrap().cast::<Checked<i64>>().is_err());
+
+    assert!(g.set("large", Checked(u128::MAX)).is_err());
+    assert!(g.get("large").unwrap().cast::<Checked<u128>>().is_err());
+    let Checked(n) = g.get("small").unwrap().cast::<Checked<i128>>().unwrap();
+    assert_eq!(n, 7);
+    assert!(lua.new_val(-1).unwrap().cast::<u128>().is_err());
+}
+
+#[test]
+fn deserialize_null() {
+    let lua = Lua::with_open_libs();
+    let text = r#"{"a":null,"b":1}"#;
+
+    let val = lua
+        .load_from_deserializer(&mut serde_json::Deserializer::from_str(text))
+        .unwrap();
+    let t = LuaTable::try_from(val).unwrap();
+    assert!(t.get("a").unwrap().is_nil());
+    assert_eq!(t.entry_count(), 1);
+
+    let val = lua
+        .load_from_deserializer_with_null(&mut serde_json::Deserializer::from_str(text))
+        .unwrap();
+    let t = LuaTable::try_from(val).unwrap();
+    assert_eq!(t.entry_count(), 2);
+    assert_eq!(
+        t.get("a").unwrap().to_pointer(),
+        lua.new_val(lua.null_value()).unwrap().to_pointer()
+    );
+    assert_eq!(serde_json::to_string(&*t).unwrap().len(), text.len());
+
+    // the nulls of the following loading are nil again
+    let val = lua
+        .load_from_deserializer(&mut serde_json::Deserializer::from_str("[null]"))
+        .unwrap();
+    assert!(LuaTable::try_from(val)
+        .unwrap()
+        .raw_get(1)
+        .unwrap()
+        .is_nil());
+}